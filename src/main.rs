@@ -1,269 +1,3799 @@
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::io;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use crossterm::event;
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{cursor, queue, terminal};
+use crossterm::style::{Color, Print, Stylize};
+use crossterm::terminal::ClearType;
 use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use qdrant_client::{Payload, Qdrant, QdrantError};
-use qdrant_client::qdrant::{CreateCollectionBuilder, Distance, PointStruct, QueryPointsBuilder, UpsertPointsBuilder, VectorParamsBuilder};
+use qdrant_client::{Payload, Qdrant};
+use qdrant_client::qdrant::{CreateCollectionBuilder, DeletePointsBuilder, Distance, PointId, PointStruct, PointsIdsList, QueryPointsBuilder, ScrollPointsBuilder, UpsertPointsBuilder, VectorParamsBuilder};
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::vectors_config;
 use serde::{Deserialize, Serialize};
+use unicode_width::UnicodeWidthStr;
 use crate::Role::System;
 
-const COMMAND_COLL_NAME: &'static str = "commands";
+const COMMAND_COLL_NAME: &str = "commands";
+// Asymmetric retrieval models are trained with the text being searched for and the text being
+// searched marked differently. Points are hashed with their prefix, so changing it re-embeds them
+const QUERY_PREFIX: &str = "query: ";
+const PASSAGE_PREFIX: &str = "passage: ";
+// Drawn before the first and following lines of input respectively
+const INPUT_PROMPT: &str = "> ";
+const INPUT_CONTINUATION: &str = "  ";
+// Assumed when regenerating hotter without a temperature set, as a typical server default
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+// Used whenever the context has somehow lost its system prompt
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant.";
+// Files larger than this are refused by @path inclusion rather than flooding the context
+const MAX_INCLUDE_BYTES: u64 = 256 * 1024;
+// Limits on the shell command, so a hung or chatty command can't stall or flood the session
+const SHELL_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_SHELL_OUTPUT_BYTES: usize = 64 * 1024;
+// How many times in a row retry may run before a new message has to be sent
+const MAX_CONSECUTIVE_RETRIES: u32 = 10;
+// Sent by the summarize command, with the reply replacing the messages it covers
+const SUMMARIZE_PROMPT: &str = "Summarize our conversation so far concisely, keeping any facts, decisions and \
+    open questions needed to continue it. Reply with only the summary.";
 
-#[derive(Parser, Debug)]
+type CommandFuture<'a> = Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + 'a>>;
+type CommandFn = Rc<Box<dyn for<'a> Fn(&'a mut ChatContext) -> CommandFuture<'a> + 'static>>;
+
+// Closures only infer a signature generic over the context borrow when given one up front
+fn command_fn<F>(f: F) -> CommandFn
+where F: for<'a> Fn(&'a mut ChatContext) -> CommandFuture<'a> + 'static {
+    Rc::new(Box::new(f))
+}
+
+#[derive(Parser, Debug, Clone)]
+// profile values are passed ahead of the real arguments, so later flags have to win
+#[command(args_override_self = true)]
 /// A TUI For Chatting with Qmulo Local AI
 struct Config {
+    #[arg(long)]
+    /// Load defaults from the named profile in ~/.config/qmuloai/config.json. Flags given on the
+    /// command line replace the profile's value for that flag, but a switch the profile turns on
+    /// can't be turned off from the command line
+    profile: Option<String>,
     #[arg(short='H', long, default_value = "localhost:8000")]
-    /// The hostname and port of the Qmulo LLM server
+    /// The hostname and port of the LLM server, prefixed with https:// to connect over TLS
     llm_host: String,
+    #[arg(long)]
+    /// Accept any TLS certificate from the LLM server, for local setups with a self-signed one.
+    /// This leaves the connection open to interception, so only use it on a trusted network
+    insecure: bool,
+    #[arg(long)]
+    /// The path generation requests are sent to, defaulting to the backend's usual route
+    endpoint_path: Option<String>,
+    #[arg(long, value_enum, default_value_t = Backend::Qmulo)]
+    /// The API the LLM server speaks
+    backend: Backend,
+    #[arg(long, env = "QMULO_API_KEY", hide_env_values = true)]
+    /// A key sent to the LLM server as a bearer token, best given through the environment
+    api_key: Option<String>,
+    #[arg(long, value_parser = parse_header)]
+    /// An extra header sent with every request to the LLM server, as "Name: value". May be repeated
+    auth_header: Vec<(String, String)>,
+    #[arg(short='m', long)]
+    /// The model to request from backends which serve more than one
+    model: Option<String>,
     #[arg(short='c', long)]
-    /// The directory where embedding models will be written to and read from on each start
-    model_cache: String,
-    #[arg(short='q', long, default_value = "localhost:6334")]
-    /// The hostname and port of the Qdrant server
-    qdrant_host: String
+    /// The directory where embedding models will be written to and read from on each start.
+    /// Defaults to qmuloai/models in the cache directory
+    model_cache: Option<String>,
+    #[arg(long, default_value = "BGELargeENV15Q", value_parser = parse_embedding_model)]
+    /// The fastembed model used to match commands, by name (e.g. BGESmallENV15) or model code
+    embedding_model: EmbeddingModel,
+    #[arg(long)]
+    /// Start loading the embedding model in the background as soon as the chat starts, so the
+    /// first command doesn't wait on it. fastembed can't memory-map the weights, so this hides
+    /// the load time rather than shortening it
+    preload_embedding_model: bool,
+    #[arg(short='q', long, default_value = "http://localhost:6334")]
+    /// The URL of the Qdrant server, either as http://host:port or bare host:port
+    qdrant_url: String,
+    #[arg(long, default_value = COMMAND_COLL_NAME)]
+    /// The Qdrant collection commands are stored in, so separate setups can share a server
+    collection_name: String,
+    #[arg(long)]
+    /// Drop and recreate the command collection if it doesn't match the embedding model
+    recreate_collection: bool,
+    #[arg(long, value_enum, default_value_t = DistanceMetric::Dot)]
+    /// The metric used to compare command embeddings. BGE models produce normalized embeddings,
+    /// so Dot ranks the same as Cosine while being cheaper to compute
+    distance: DistanceMetric,
+    #[arg(long)]
+    /// The score a command must reach before it is executed: a minimum similarity for Cosine and
+    /// Dot, or a maximum distance for Euclid. Defaults to a value suited to the distance metric
+    command_threshold: Option<f32>,
+    #[arg(long, default_value_t = 0.02)]
+    /// When other commands score within this much of the best match, ask which one was meant
+    /// instead of running the best one. 0 always runs the best match
+    disambiguation_delta: f32,
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(1..))]
+    /// How many command texts are embedded at once when syncing commands. Larger batches are
+    /// faster with many custom commands, smaller ones use less memory
+    embed_batch_size: u16,
+    #[arg(long)]
+    /// Request a streamed response and print tokens as they arrive
+    stream: bool,
+    #[arg(long)]
+    /// Buffer streamed output and print it a word at a time, or after this many milliseconds
+    /// without a word boundary, to smooth out bursts of tokens
+    stream_flush_ms: Option<u64>,
+    #[arg(long, default_value_t = 120)]
+    /// How long the LLM server may go without sending anything before the request is given up on
+    timeout_secs: u64,
+    #[arg(long, default_value_t = 3)]
+    /// How many times to retry a request when the server is unavailable or errors
+    max_retries: u32,
+    #[arg(long, default_value_t = 60)]
+    /// Open a fresh connection to the LLM server when no request has been made for this many
+    /// seconds, since idle keep-alive connections are often dropped. 0 always reuses connections
+    idle_reconnect_secs: u64,
+    #[arg(long)]
+    /// Read the system prompt from this file instead of asking for it at startup. May be repeated
+    /// to layer prompts, which are joined in order into one system message since many models'
+    /// chat templates only honour a single leading system message
+    system_prompt_file: Vec<String>,
+    #[arg(long)]
+    /// Where the conversation is saved after each change for recovery after a crash. Defaults to
+    /// qmuloai/recovery-<pid>.json in the local data directory, one file per session
+    recovery_file: Option<String>,
+    #[arg(long)]
+    /// Append every message to this file as a line of json as the session goes, for a transcript
+    /// which survives crashes
+    log_file: Option<String>,
+    #[arg(long)]
+    /// Print how long the server took to generate each response
+    show_timings: bool,
+    #[arg(long)]
+    /// Wrap responses to the terminal width at word boundaries, leaving code blocks as they are.
+    /// Streamed responses are printed as they arrive and are not wrapped
+    wrap: bool,
+    #[arg(long)]
+    /// Render markdown in responses with terminal styling. Ignored when output isn't a terminal
+    render_markdown: bool,
+    #[arg(long)]
+    /// Strip <think> reasoning sections from responses, so they are neither shown nor sent back
+    /// to the model. They are still kept for save and export. Streamed responses are printed as
+    /// they arrive and are not stripped on screen
+    hide_thinking: bool,
+    #[arg(long)]
+    /// Fold system messages into the user message that follows them when sending, for servers
+    /// which only accept user and assistant turns. The conversation itself is left as it is
+    merge_system_into_first_user: bool,
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    /// Label responses with a colored role prefix. Auto does so only on a terminal when NO_COLOR
+    /// isn't set
+    color: ColorChoice,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    /// Print each response as a line of json with its generation time rather than as text.
+    /// Responses aren't streamed in this format
+    output_format: OutputFormat,
+    #[arg(long, default_value_t = InputOptions::default().warn)]
+    /// Ask for confirmation before sending a message longer than this many characters
+    input_warn_chars: usize,
+    #[arg(long, default_value_t = InputOptions::default().max)]
+    /// Stop accepting input once a message reaches this many characters
+    input_max_chars: usize,
+    #[arg(long, value_enum, default_value_t = InputOptions::default().submit)]
+    /// Which key sends a message, with the other one starting a new line
+    submit_key: SubmitKey,
+    #[arg(long, default_value_t = 80)]
+    /// How many characters of each message the messages command shows
+    preview_width: usize,
+    #[arg(long)]
+    /// Drop the oldest messages, keeping the system prompt, once the conversation grows past this many
+    max_context_messages: Option<usize>,
+    #[arg(long)]
+    /// The sampling temperature, left to the server's default if unset
+    temperature: Option<f32>,
+    #[arg(long)]
+    /// The nucleus sampling probability mass, left to the server's default if unset
+    top_p: Option<f32>,
+    #[arg(long)]
+    /// The maximum number of tokens to generate, left to the server's default if unset
+    max_tokens: Option<u32>,
+    #[arg(long)]
+    /// A JSON file of additional commands, each injecting a system message or prompt template
+    commands_file: Option<String>,
+    #[arg(long)]
+    /// A directory of system prompts, one per file, each selectable by its file name as a
+    /// persona. Defaults to qmuloai/personas in the config directory, if it exists
+    persona_dir: Option<String>,
+    #[arg(long)]
+    /// Disable slash commands entirely, so neither the embedding model nor Qdrant are ever used
+    no_commands: bool,
+    #[arg(long)]
+    /// Offer the shell command, which runs what it's given and adds the output to the conversation
+    allow_shell: bool,
+    #[arg(long)]
+    /// Print the request that would be sent to the LLM server instead of sending it
+    dry_run: bool,
+    #[arg(short, long)]
+    /// Log embedding and Qdrant operations with their timings to stderr
+    verbose: bool,
+    #[arg(long)]
+    /// Check that the LLM server and Qdrant are reachable and the embedding model loads, then exit
+    check: bool,
+    #[arg(short, long)]
+    /// Send this message, print the reply and exit rather than starting a chat
+    prompt: Option<String>,
+    #[arg(long)]
+    /// Report errors as lines of json on stderr, for tools wrapping the chat
+    json_errors: bool,
 }
 
 struct ChatContext {
     endpoint: String,
+    endpoint_path: String,
+    backend: Backend,
+    model: Option<String>,
+    sampling: SamplingParams,
+    agent: ureq::Agent,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    insecure: bool,
+    max_retries: u32,
+    idle_reconnect: Option<Duration>,
+    last_request: Option<Instant>,
     context: Vec<Message>,
+    // Copies of the context saved by fork, most recent last
+    forks: Vec<Vec<Message>>,
+    // Jotted down with the note command, never sent to the server
+    notes: Vec<String>,
+    stats: SessionStats,
+    // retries since the last new message, capped in case retry ends up in a loop
+    consecutive_retries: u32,
     // Enable using the chat without Qdrant/embeddings if no commands are ever executed
     embedding_model: Option<TextEmbedding>,
-    qclient: Qdrant,
+    embedding_model_kind: EmbeddingModel,
+    preloading: Option<std::sync::mpsc::Receiver<Result<TextEmbedding, anyhow::Error>>>,
+    qclient: Option<Qdrant>,
+    model_cache: PathBuf,
+    qdrant_url: String,
+    collection_name: String,
+    recreate_collection: bool,
     commands: BTreeMap<String, Command>,
+    // the file custom commands were loaded from, and each of their definitions as loaded
+    commands_file: Option<PathBuf>,
+    custom_commands: BTreeMap<String, String>,
+    commands_enabled: bool,
+    allow_shell: bool,
+    // Everything entered so far, for recalling previous input with the arrow keys
+    history: Vec<String>,
+    input_options: InputOptions,
+    // the configuration as given at startup, for whoami
+    config: Config,
+    // Set by the quit command so the main loop knows to stop once the command returns
+    quitting: bool,
+    distance: DistanceMetric,
+    command_threshold: f32,
+    disambiguation_delta: f32,
+    embed_batch_size: usize,
+    stream: bool,
+    stream_flush: Option<Duration>,
+    show_timings: bool,
+    wrap: bool,
+    render_markdown: bool,
+    hide_thinking: bool,
+    merge_system: bool,
+    color: bool,
+    output_format: OutputFormat,
+    preview_width: usize,
+    max_context_messages: Option<usize>,
+    recovery_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    dry_run: bool,
+    verbose: bool,
 }
 
 impl ChatContext {
     fn new(config: &Config, sys_prompt: String) -> Result<Self, anyhow::Error> {
         let commands = BTreeMap::new();
-        let qclient = Qdrant::from_url(format!("http://{}", config.qdrant_host).as_str()).build()
-            .context("Failed to build Qdrant vector db client")?;
+        let timeout = Duration::from_secs(config.timeout_secs);
+        if config.insecure {
+            eprintln!("// Warning: --insecure is set, the LLM server's TLS certificate won't be verified");
+        }
+        let endpoint_path = config.endpoint_path.clone().unwrap_or(config.backend.path().to_string());
+        let endpoint = endpoint_url(&config.llm_host, &endpoint_path)?;
         Ok(Self {
-            endpoint: format!("http://{}/generate", config.llm_host),
-            context: vec![Message{role: System, content: sys_prompt}],
+            endpoint,
+            endpoint_path,
+            backend: config.backend,
+            model: config.model.clone(),
+            sampling: SamplingParams{
+                temperature: config.temperature,
+                top_p: config.top_p,
+                max_tokens: config.max_tokens,
+            },
+            agent: build_agent(timeout, config.insecure)?,
+            headers: config.api_key.iter()
+                .map(|key| ("authorization".to_string(), format!("Bearer {}", key)))
+                .chain(config.auth_header.iter().cloned())
+                .collect(),
+            timeout,
+            insecure: config.insecure,
+            max_retries: config.max_retries,
+            idle_reconnect: Some(Duration::from_secs(config.idle_reconnect_secs)).filter(|idle| !idle.is_zero()),
+            last_request: None,
+            context: vec![Message::system(sys_prompt)],
+            forks: Vec::new(),
+            notes: Vec::new(),
+            stats: SessionStats::default(),
+            consecutive_retries: 0,
             embedding_model: None,
-            qclient,
-            commands
+            embedding_model_kind: config.embedding_model.clone(),
+            preloading: None,
+            qclient: None,
+            model_cache: model_cache_path(config)?,
+            qdrant_url: with_scheme(&config.qdrant_url),
+            collection_name: config.collection_name.clone(),
+            recreate_collection: config.recreate_collection,
+            commands,
+            commands_file: None,
+            custom_commands: BTreeMap::new(),
+            commands_enabled: !config.no_commands,
+            allow_shell: config.allow_shell,
+            history: Vec::new(),
+            input_options: config.into(),
+            config: config.clone(),
+            quitting: false,
+            distance: config.distance,
+            command_threshold: config.command_threshold.unwrap_or(config.distance.default_threshold()),
+            disambiguation_delta: config.disambiguation_delta,
+            embed_batch_size: config.embed_batch_size.into(),
+            stream: config.stream,
+            stream_flush: config.stream_flush_ms.map(Duration::from_millis),
+            show_timings: config.show_timings,
+            wrap: config.wrap,
+            render_markdown: config.render_markdown,
+            hide_thinking: config.hide_thinking,
+            merge_system: config.merge_system_into_first_user,
+            color: config.color.enabled(),
+            output_format: config.output_format,
+            preview_width: config.preview_width,
+            max_context_messages: config.max_context_messages,
+            recovery_file: recovery_path(config),
+            log_file: config.log_file.as_deref().map(expand_tilde),
+            dry_run: config.dry_run,
+            verbose: config.verbose,
         })
     }
-    async fn initialize_commands(&mut self) -> Result<(), anyhow::Error> {
-        self.embedding_model = Some(TextEmbedding::try_new(
-            InitOptions::new(EmbeddingModel::BGELargeENV15Q)
-                .with_show_download_progress(true)
-                .with_cache_dir(PathBuf::from("~/Projects/llms/models/"))
-        ).context("Failed to load local embedding model")?);
-        match self.qclient.create_collection(
-            CreateCollectionBuilder::new(COMMAND_COLL_NAME)
-                .vectors_config(VectorParamsBuilder::new(1024, Distance::Dot))).await {
-            Ok(_) => {},
-            Err(e) => {
-                match &e {
-                    QdrantError::ResponseError{status} => {
-                        if status.code() != tonic::Code::AlreadyExists {
-                            return Err(e.into())
-                        }
-                    },
-                    _ => return Err(e.into())
-                }
-            }
-        }
+    fn initialize_commands(&mut self) {
         self.commands.insert("retry".into(), Command{
             id: "retry".into(),
             description: "delete the last assistant response and regenerate it again, or retry the last response".into(),
-            f: Rc::new(Box::new(|ctx| {
-                ctx.context.pop();
-                ctx.send_context()?;
-                println!("{}", ctx.context[ctx.context.len() - 1].content);
+            aliases: vec!["try again".into(), "redo that".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                if ctx.consecutive_retries >= MAX_CONSECUTIVE_RETRIES {
+                    return Err(anyhow::Error::msg(format!(
+                        "retried {} times in a row, send a new message before retrying again", ctx.consecutive_retries)));
+                }
+                let original = ctx.snapshot();
+                // only ever drop an assistant reply, never the system prompt or the user's message
+                if ctx.context.len() > 1 && matches!(ctx.context.last(), Some(Message{role: Role::Assistant, ..})) {
+                    ctx.context.pop();
+                }
+                if !matches!(ctx.context.last(), Some(Message{role: Role::User, ..})) {
+                    ctx.restore(original);
+                    return Err(anyhow::Error::msg("there is no message to retry a response to"));
+                }
+                if let Err(err) = ctx.send_context().await {
+                    ctx.restore(original);
+                    return Err(err);
+                }
+                ctx.consecutive_retries += 1;
+                Ok(())
+            })),
+        });
+        self.commands.insert("regenerate".into(), Command{
+            id: "regenerate".into(),
+            description: "regenerate the last response at a higher temperature, to get a more creative or different answer".into(),
+            aliases: vec!["give me a different answer".into(), "be more creative".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let current = ctx.sampling.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+                println!("// Enter a temperature for this response, or leave empty for {:.1}:", current + 0.3);
+                let input = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let temperature = match input.trim() {
+                    "" => current + 0.3,
+                    value => value.parse().with_context(|| format!("'{}' is not a valid temperature", value))?,
+                };
+                let original = ctx.snapshot();
+                if ctx.context.len() > 1 && matches!(ctx.context.last(), Some(Message{role: Role::Assistant, ..})) {
+                    ctx.context.pop();
+                }
+                if !matches!(ctx.context.last(), Some(Message{role: Role::User, ..})) {
+                    ctx.restore(original);
+                    return Err(anyhow::Error::msg("there is no message to regenerate a response to"));
+                }
+                let sampling = SamplingParams{temperature: Some(temperature), ..ctx.sampling};
+                if let Err(err) = ctx.send_context_with(sampling).await {
+                    ctx.restore(original);
+                    return Err(err);
+                }
+                Ok(())
+            })),
+        });
+        self.commands.insert("params".into(), Command{
+            id: "params".into(),
+            description: "Set the temperature, top_p and max_tokens used for the rest of the session, optionally saving them to the profile.".into(),
+            aliases: vec!["sampling settings".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let mut sampling = ctx.sampling;
+                println!("// For each setting enter a value, nothing to keep it, or 'none' to leave it to the server");
+                println!("// Temperature ({}):", describe_param(sampling.temperature));
+                sampling.temperature = parse_param(&read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?, "temperature", sampling.temperature)?;
+                println!("// top_p ({}):", describe_param(sampling.top_p));
+                sampling.top_p = parse_param(&read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?, "top_p", sampling.top_p)?;
+                println!("// max_tokens ({}):", describe_param(sampling.max_tokens));
+                sampling.max_tokens = parse_param(&read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?, "max_tokens", sampling.max_tokens)?;
+                ctx.sampling = sampling;
+                println!("// Sampling: {}", serde_json::to_string(&ctx.sampling)?);
+                if let Some(profile) = ctx.config.profile.clone() {
+                    if confirm(&format!("Save these to profile '{}'?", profile))? {
+                        let path = save_profile_sampling(&profile, sampling)?;
+                        println!("// Saved to profile '{}' in {}", profile, path.display());
+                    }
+                }
                 Ok(())
             })),
         });
         self.commands.insert("hint".into(), Command{
             id: "hint".into(),
             description: "add a message in the system role, further clarifying how the assistant should behave, or providing a suggestion for future responses.".into(),
-            f: Rc::new(Box::new(|ctx| {
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
                 println!("// Enter your hint below:");
-                match read_message()? {
-                    InputType::Prompt(prompt) => {
-                        ctx.context.push(Message::system(prompt));
-                        Ok(())
-                    }
-                    InputType::Command(_) => {
-                        Err(anyhow::Error::msg("unable to process command input inside command shell"))
-                    }
-                }
+                let hint = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                ctx.push_system(hint);
+                Ok(())
             })),
         });
         self.commands.insert("system".into(), Command{
             id: "system".into(),
             description: "Overwrite the system prompt with a new one.".into(),
-            f: Rc::new(Box::new(|ctx| {
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
                 println!("// Enter the new system prompt below:");
-                match read_message()? {
-                    InputType::Prompt(prompt) => {
-                        ctx.context.get_mut(0).unwrap().content = prompt;
-                        Ok(())
+                let prompt = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                ctx.set_system_prompt(prompt);
+                Ok(())
+            }))
+        });
+        self.commands.insert("save".into(), Command{
+            id: "save".into(),
+            description: "Save the current conversation to a file so it can be restored later.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the file to save the conversation to:");
+                let path = expand_tilde(read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?.trim());
+                // without notes the file stays a plain list of messages, as older versions wrote
+                let json = if ctx.notes.is_empty() {
+                    serde_json::to_string_pretty(&ctx.context)?
+                } else {
+                    serde_json::to_string_pretty(&serde_json::json!({"messages": ctx.context, "notes": ctx.notes}))?
+                };
+                std::fs::write(&path, json)
+                    .with_context(|| format!("Failed to write conversation to {}", path.display()))?;
+                println!("// Saved conversation to {}", path.display());
+                Ok(())
+            }))
+        });
+        self.commands.insert("export".into(), Command{
+            id: "export".into(),
+            description: "Export the conversation as a readable markdown transcript for sharing.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the markdown file to export the conversation to:");
+                let path = expand_tilde(read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?.trim());
+                std::fs::write(&path, conversation_markdown(&ctx.context, &ctx.notes))
+                    .with_context(|| format!("Failed to write transcript to {}", path.display()))?;
+                println!("// Exported conversation to {}", path.display());
+                Ok(())
+            }))
+        });
+        self.commands.insert("load".into(), Command{
+            id: "load".into(),
+            description: "Load a previously saved conversation from a file, replacing the current one.".into(),
+            aliases: Vec::new(),
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the file to load the conversation from:");
+                let path = expand_tilde(read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?.trim());
+                // only replace the context once the whole file has parsed
+                let saved = read_conversation(&path)?;
+                if !matches!(saved.messages.first(), Some(Message{role: System, ..})) {
+                    println!("// Warning: the loaded conversation does not start with a system prompt");
+                }
+                ctx.restore(saved.messages);
+                ctx.notes = saved.notes;
+                println!("// Loaded {} messages and {} notes from {}", ctx.context.len(), ctx.notes.len(), path.display());
+                Ok(())
+            }))
+        });
+        self.commands.insert("replay".into(), Command{
+            id: "replay".into(),
+            description: "Replay the user messages of a saved conversation against the current server, comparing the new responses to the saved ones.".into(),
+            aliases: Vec::new(),
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the file of the conversation to replay:");
+                let path = expand_tilde(read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?.trim());
+                let saved = read_conversation(&path)?.messages;
+                let mut messages = saved.iter().peekable();
+                let system = match messages.peek() {
+                    Some(Message{role: System, ..}) => messages.next().unwrap().clone(),
+                    _ => Message::system(ctx.context.first().map(|m| m.content.clone()).unwrap_or_default()),
+                };
+                let original = ctx.snapshot();
+                ctx.restore(vec![system]);
+                let (mut turns, mut changed) = (0, 0);
+                while let Some(message) = messages.next() {
+                    match message.role {
+                        // hints are replayed where they were, replies are only compared against
+                        Role::System => ctx.push_system(message.content.clone()),
+                        Role::Assistant => {}
+                        Role::User => {
+                            turns += 1;
+                            println!("> {}", preview(&message.content, ctx.preview_width));
+                            // a replay cut short goes back to the conversation from before it
+                            if let Err(err) = ctx.send_user_message(message.content.clone()).await {
+                                ctx.restore(original);
+                                return Err(err.context(format!("Replay stopped at turn {}", turns)));
+                            }
+                            let Some(Message{role: Role::Assistant, content: expected, ..}) = messages.peek() else {
+                                continue;
+                            };
+                            if ctx.context.last().map(|reply| &reply.content) != Some(expected) {
+                                changed += 1;
+                                println!("// Turn {} differs from the saved response", turns);
+                            }
+                        }
+                    }
+                }
+                println!("// Replayed {} turns from {}, {} responses differ from the saved ones", turns, path.display(), changed);
+                Ok(())
+            }))
+        });
+        self.commands.insert("clear".into(), Command{
+            id: "clear".into(),
+            description: "Clear the conversation and start over, keeping only the system prompt.".into(),
+            aliases: vec!["start over".into(), "new conversation".into()],
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                ctx.context.truncate(1);
+                if ctx.context.is_empty() {
+                    ctx.context.push(Message::system(DEFAULT_SYSTEM_PROMPT.into()));
+                }
+                println!("// Cleared the conversation, keeping the system prompt");
+                Ok(())
+            }))
+        });
+        self.commands.insert("undo".into(), Command{
+            id: "undo".into(),
+            description: "Undo the last exchange, removing both my last message and the assistant's response to it.".into(),
+            aliases: vec!["take that back".into(), "forget my last message".into()],
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                let len = ctx.context.len();
+                // never reach back into index 0, which is the system prompt
+                let has_pair = len >= 3
+                    && matches!(ctx.context[len - 1].role, Role::Assistant)
+                    && matches!(ctx.context[len - 2].role, Role::User);
+                if !has_pair {
+                    println!("// Nothing to undo");
+                    return Ok(());
+                }
+                ctx.context.truncate(len - 2);
+                println!("// Removed the last exchange");
+                Ok(())
+            }))
+        });
+        self.commands.insert("list".into(), Command{
+            id: "list".into(),
+            description: "List all of the available commands and what they do, or show help.".into(),
+            aliases: vec!["help".into(), "what can you do".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                for command in ctx.commands.values() {
+                    println!("// {}: {}", command.id, command.description);
+                    if !command.aliases.is_empty() {
+                        println!("//   also: {}", command.aliases.join(", "));
+                    }
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("tokens".into(), Command{
+            id: "tokens".into(),
+            description: "Show how large the conversation is, counting the characters and estimated tokens in the context.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let chars: usize = ctx.context.iter().map(|message| message.content.chars().count()).sum();
+                // roughly four characters per token for english text, good enough to gauge the window
+                println!("// {} messages, {} characters, ~{} tokens", ctx.context.len(), chars, chars.div_ceil(4));
+                Ok(())
+            }))
+        });
+        self.commands.insert("stats".into(), Command{
+            id: "stats".into(),
+            description: "Show statistics for this session, such as how many messages were exchanged and the time spent generating.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let stats = ctx.stats;
+                println!("// {} user turns, {} replies", stats.user_turns, stats.replies);
+                println!("// {} characters sent, {} received", stats.chars_sent, stats.chars_received);
+                if stats.timed_replies > 0 {
+                    println!("// {:.2}s generating, {:.2}s per reply on average",
+                        stats.generation_secs, stats.generation_secs / stats.timed_replies as f32);
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("whoami".into(), Command{
+            id: "whoami".into(),
+            description: "Show the configuration in effect, such as the LLM endpoint, embedding model and sampling settings.".into(),
+            aliases: vec!["show settings".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                if let Some(profile) = &ctx.config.profile {
+                    println!("// Profile: {}", profile);
+                }
+                println!("// Endpoint: {} ({:?})", ctx.endpoint, ctx.backend);
+                println!("// Model: {}", ctx.model.as_deref().unwrap_or("server default"));
+                // header values are never shown, only which headers are sent
+                let headers: Vec<&str> = ctx.headers.iter().map(|(name, _)| name.as_str()).collect();
+                println!("// Auth: {}", if headers.is_empty() { "none".to_string() } else { format!("{} (redacted)", headers.join(", ")) });
+                println!("// Sampling: {}", serde_json::to_string(&ctx.sampling)?);
+                println!("// Embedding model: {:?} cached in {}", ctx.embedding_model_kind, ctx.model_cache.display());
+                println!("// Qdrant: {} collection '{}' ({:?}, threshold {})",
+                    ctx.qdrant_url, ctx.collection_name, ctx.distance, ctx.command_threshold);
+                if let Some(path) = &ctx.recovery_file {
+                    println!("// Recovery file: {}", path.display());
+                }
+                if let Some(path) = &ctx.log_file {
+                    println!("// Log file: {}", path.display());
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("messages".into(), Command{
+            id: "messages".into(),
+            description: "Show every message in the conversation along with its index and role.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                for (idx, message) in ctx.context.iter().enumerate() {
+                    println!("// [{}] {}: {}", idx, message.role.as_str(), preview(&message.content, ctx.preview_width));
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("delete".into(), Command{
+            id: "delete".into(),
+            description: "Delete a single message from the conversation by its index, as shown by the messages command.".into(),
+            aliases: Vec::new(),
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the index of the message to delete:");
+                let input = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let idx: usize = input.trim().parse()
+                    .with_context(|| format!("'{}' is not a valid message index", input.trim()))?;
+                if idx == 0 {
+                    return Err(anyhow::Error::msg("the system prompt can't be deleted, use the system command to change it"));
+                }
+                if idx >= ctx.context.len() {
+                    return Err(anyhow::Error::msg(format!("there is no message {}, the last is {}", idx, ctx.context.len() - 1)));
+                }
+                let message = ctx.context.remove(idx);
+                println!("// Deleted [{}] {}: {}", idx, message.role.as_str(), preview(&message.content, ctx.preview_width));
+                Ok(())
+            }))
+        });
+        self.commands.insert("context-limit".into(), Command{
+            id: "context-limit".into(),
+            description: "Set how many messages the conversation may hold before the oldest are dropped, or remove the limit.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the maximum number of messages to keep, or nothing to remove the limit:");
+                let input = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                ctx.max_context_messages = match input.trim() {
+                    "" => None,
+                    limit => Some(limit.parse().with_context(|| format!("'{}' is not a valid message count", limit))?),
+                };
+                match ctx.max_context_messages {
+                    Some(limit) => {
+                        let dropped = ctx.trim_context();
+                        println!("// Keeping at most {} messages, dropped {}", limit, dropped);
+                    }
+                    None => println!("// Context limit removed"),
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("summarize".into(), Command{
+            id: "summarize".into(),
+            description: "Condense the conversation so far into a summary, replacing the older messages to free up context.".into(),
+            aliases: Vec::new(),
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                // the system prompt and latest exchange are kept as they are
+                let keep = 2;
+                if ctx.context.len() <= keep + 2 {
+                    return Err(anyhow::Error::msg("the conversation is too short to summarize"));
+                }
+                let original = ctx.snapshot();
+                ctx.context.push(Message::user(SUMMARIZE_PROMPT.to_string()));
+                println!("// Summary:");
+                // the request is only a means to the summary, so it isn't logged, saved or counted
+                let reply = ctx.request_reply(ctx.sampling).await;
+                ctx.restore(original.clone());
+                let Some(reply) = reply? else {
+                    // nothing came back, as with --dry-run
+                    return Ok(());
+                };
+                let summary = Message::system(format!("Summary of the earlier conversation: {}", reply.message.content));
+                ctx.append_transcript(&summary);
+                let mut context = vec![original[0].clone(), summary];
+                context.extend_from_slice(&original[original.len() - keep..]);
+                println!("// Replaced {} messages with the summary", original.len() - 1 - keep);
+                ctx.restore(context);
+                ctx.autosave();
+                Ok(())
+            }))
+        });
+        self.commands.insert("model".into(), Command{
+            id: "model".into(),
+            description: "Switch to a different LLM server host, keeping the current conversation.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Currently sending to {}", ctx.endpoint);
+                println!("// Enter the new host and port:");
+                let host = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let endpoint = endpoint_url(host.trim(), &ctx.endpoint_path)?;
+                if let Err(err) = probe_server(ctx.agent.clone(), with_scheme(host.trim())).await {
+                    return Err(anyhow::Error::msg(format!("{} is unreachable, keeping {}: {}", host.trim(), ctx.endpoint, err)));
+                }
+                println!("// Now sending to {}", endpoint);
+                ctx.endpoint = endpoint;
+                Ok(())
+            }))
+        });
+        self.commands.insert("raw".into(), Command{
+            id: "raw".into(),
+            description: "Send a hand-written JSON body straight to the LLM server and show its raw response, leaving the conversation untouched.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the JSON body to send to {}:", ctx.endpoint);
+                let input = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let body: serde_json::Value = serde_json::from_str(&input).context("The body is not valid JSON")?;
+                let endpoint = ctx.endpoint.clone();
+                let agent = ctx.agent.clone();
+                let headers = ctx.headers.clone();
+                let (timeout, max_retries) = (ctx.timeout, ctx.max_retries);
+                let spinner = Spinner::start();
+                let request_spinner = spinner.clone();
+                let result = tokio::task::spawn_blocking(move || -> Result<(u16, String), anyhow::Error> {
+                    let response = post_with_retries(&agent, &endpoint, &headers, &body, false, max_retries, &request_spinner)
+                        .map_err(|e| describe_request_error(&endpoint, timeout, *e))?;
+                    Ok((response.status(), response.into_string()?))
+                }).await;
+                spinner.stop();
+                let (status, raw) = result??;
+                println!("// HTTP {}", status);
+                match serde_json::from_str::<serde_json::Value>(&raw) {
+                    Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+                    Err(_) => println!("{}", raw),
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("reload".into(), Command{
+            id: "reload".into(),
+            description: "Reload the custom commands file, picking up any commands which were added, changed or removed.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let Some(path) = ctx.commands_file.clone() else {
+                    return Err(anyhow::Error::msg("no commands file was loaded, start with --commands-file to use one"));
+                };
+                let [added, changed, removed] = ctx.load_commands_file(&path)?;
+                // without a connection the points are brought up to date on the next match instead
+                if ctx.qclient.is_some() {
+                    ctx.sync_command_points().await?;
+                }
+                for (label, ids) in [("Added", added), ("Changed", changed), ("Removed", removed)] {
+                    if !ids.is_empty() {
+                        println!("// {}: {}", label, ids.join(", "));
+                    }
+                }
+                println!("// Reloaded commands from {}", path.display());
+                Ok(())
+            }))
+        });
+        self.commands.insert("quit".into(), Command{
+            id: "quit".into(),
+            description: "Quit the chat and exit the program.".into(),
+            aliases: vec!["exit".into(), "goodbye".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                ctx.quitting = true;
+                Ok(())
+            }))
+        });
+        self.commands.insert("fork".into(), Command{
+            id: "fork".into(),
+            description: "Fork the conversation, saving a snapshot to come back to before trying something different.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let snapshot = ctx.snapshot();
+                ctx.forks.push(snapshot);
+                println!("// Forked the conversation, {} snapshot(s) deep", ctx.forks.len());
+                Ok(())
+            }))
+        });
+        self.commands.insert("unfork".into(), Command{
+            id: "unfork".into(),
+            description: "Go back to the most recent fork, restoring the conversation as it was when it was forked.".into(),
+            aliases: Vec::new(),
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                match ctx.forks.pop() {
+                    Some(snapshot) => {
+                        ctx.restore(snapshot);
+                        println!("// Restored the last fork, {} snapshot(s) deep", ctx.forks.len());
+                    }
+                    None => println!("// There are no forks to go back to"),
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("match".into(), Command{
+            id: "match".into(),
+            description: "Show how closely each command matches a phrase and which would run, without running any of them.".into(),
+            aliases: vec!["explain".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter a phrase to match against the commands:");
+                let phrase = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let ranked = ctx.rank_commands(phrase.trim()).await?;
+                let Some(&(_, best)) = ranked.first() else {
+                    println!("// No commands are stored in '{}'", ctx.collection_name);
+                    return Ok(());
+                };
+                let mut candidates = Vec::new();
+                for (idx, (id, score)) in ranked.iter().enumerate() {
+                    let passes = ctx.distance.matches(*score, ctx.command_threshold);
+                    // mirrors match_command, which only asks among the run of close scores at the top
+                    if passes && candidates.len() == idx && (score - best).abs() <= ctx.disambiguation_delta {
+                        candidates.push(id.as_str());
+                    }
+                    println!("//   {}. {} {:.4}{}", idx + 1, id, score, if passes { "" } else { " (misses the threshold)" });
+                }
+                match candidates[..] {
+                    [] => println!("// Nothing would run, the threshold is {}", ctx.command_threshold),
+                    [id] => println!("// Would run '{}'", id),
+                    _ => println!("// Would ask which of {} was meant", candidates.join(", ")),
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("note".into(), Command{
+            id: "note".into(),
+            description: "Jot down a note for yourself, kept with the session but never sent to the assistant.".into(),
+            aliases: vec!["remember this".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter the note:");
+                let note = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                if note.trim().is_empty() {
+                    println!("// Nothing to note");
+                    return Ok(());
+                }
+                ctx.notes.push(note);
+                println!("// Noted, {} note(s) so far", ctx.notes.len());
+                Ok(())
+            }))
+        });
+        self.commands.insert("notes".into(), Command{
+            id: "notes".into(),
+            description: "Show the notes jotted down with the note command.".into(),
+            aliases: vec!["show my notes".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                if ctx.notes.is_empty() {
+                    println!("// There are no notes");
+                }
+                for (idx, note) in ctx.notes.iter().enumerate() {
+                    println!("// [{}] {}", idx, note.replace('\n', "\n//     "));
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("branch-list".into(), Command{
+            id: "branch-list".into(),
+            description: "List every saved fork with its index, so any of them can be restored.".into(),
+            aliases: vec!["list forks".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                if ctx.forks.is_empty() {
+                    println!("// There are no forks");
+                }
+                for (idx, snapshot) in ctx.forks.iter().enumerate() {
+                    let first = snapshot.iter().find(|message| matches!(message.role, Role::User))
+                        .map(|message| preview(&message.content, ctx.preview_width))
+                        .unwrap_or_else(|| "(no user messages)".into());
+                    println!("// [{}] depth {}, {} messages: {}", idx, idx + 1, snapshot.len(), first);
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("restore".into(), Command{
+            id: "restore".into(),
+            description: "Restore the conversation to any saved fork by its index, as shown by the branch-list command.".into(),
+            aliases: vec!["switch fork".into()],
+            destructive: true,
+            f: command_fn(|ctx| Box::pin(async move {
+                if ctx.forks.is_empty() {
+                    println!("// There are no forks to restore");
+                    return Ok(());
+                }
+                println!("// Enter the index of the fork to restore:");
+                let input = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let idx: usize = input.trim().parse()
+                    .with_context(|| format!("'{}' is not a valid fork index", input.trim()))?;
+                // unlike unfork, the fork stays saved so it can be come back to again
+                let Some(snapshot) = ctx.forks.get(idx).cloned() else {
+                    return Err(anyhow::Error::msg(format!("there is no fork {}, the last is {}", idx, ctx.forks.len() - 1)));
+                };
+                ctx.restore(snapshot);
+                println!("// Restored fork [{}] with {} messages", idx, ctx.context.len());
+                Ok(())
+            }))
+        });
+        self.commands.insert("diff".into(), Command{
+            id: "diff".into(),
+            description: "Compare two responses line by line, such as the latest one against the latest one in the most recent fork.".into(),
+            aliases: vec!["compare responses".into()],
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                println!("// Enter two message indices to compare, or nothing for the latest response against the last fork:");
+                let input = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                let last_reply = |messages: &[Message]| messages.iter().rev()
+                    .find(|message| matches!(message.role, Role::Assistant))
+                    .map(|message| message.content.clone());
+                let (old, new) = match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [] => {
+                        let fork = ctx.forks.last()
+                            .ok_or_else(|| anyhow::Error::msg("there are no forks to compare against"))?;
+                        match (last_reply(fork), last_reply(&ctx.context)) {
+                            (Some(old), Some(new)) => (old, new),
+                            _ => return Err(anyhow::Error::msg("the fork and the conversation both need a response to compare")),
+                        }
                     }
-                    InputType::Command(_) => {
-                        Err(anyhow::Error::msg("unable to process command input inside command shell"))
+                    [first, second] => {
+                        let pick = |index: &str| -> Result<String, anyhow::Error> {
+                            let idx: usize = index.parse()
+                                .with_context(|| format!("'{}' is not a valid message index", index))?;
+                            ctx.context.get(idx).map(|message| message.content.clone())
+                                .ok_or_else(|| anyhow::Error::msg(format!("there is no message {}", idx)))
+                        };
+                        (pick(first)?, pick(second)?)
                     }
+                    _ => return Err(anyhow::Error::msg("expected two message indices, or nothing")),
+                };
+                if old == new {
+                    println!("// No differences");
+                    return Ok(());
                 }
+                for (change, line) in diff_lines(&old, &new) {
+                    let line = format!("{} {}", change, line);
+                    match (ctx.color, change) {
+                        (true, '-') => println!("{}", line.red()),
+                        (true, '+') => println!("{}", line.green()),
+                        _ => println!("{}", line),
+                    }
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("edit".into(), Command{
+            id: "edit".into(),
+            description: "Edit my last message, replacing it with a new one and regenerating the response.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let mut idx = ctx.context.len().saturating_sub(1);
+                if matches!(ctx.context.last(), Some(Message{role: Role::Assistant, ..})) {
+                    idx = idx.saturating_sub(1);
+                }
+                if idx == 0 || !matches!(ctx.context[idx].role, Role::User) {
+                    println!("// There is no user message to edit");
+                    return Ok(());
+                }
+                println!("// Editing: {}", ctx.context[idx].content);
+                println!("// Enter the replacement message below:");
+                // read before touching the context so cancelling, or a file that can't be included,
+                // leaves it as it was
+                let prompt = include_files(&read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?)?;
+                let original = ctx.snapshot();
+                ctx.context.truncate(idx + 1);
+                ctx.context[idx].content = prompt;
+                let edited = ctx.context[idx].clone();
+                // a failed request leaves the original message and its reply in place
+                if let Err(err) = ctx.send_context_after(&edited).await {
+                    ctx.restore(original);
+                    return Err(err);
+                }
+                Ok(())
+            }))
+        });
+        self.commands.insert("copy".into(), Command{
+            id: "copy".into(),
+            description: "Copy the last response to the clipboard.".into(),
+            aliases: Vec::new(),
+            destructive: false,
+            f: command_fn(|ctx| Box::pin(async move {
+                let Some(message) = ctx.context.last() else {
+                    println!("// There is nothing to copy");
+                    return Ok(());
+                };
+                copy_to_clipboard(&message.content)?;
+                println!("// Copied the last {:?} message to the clipboard", message.role);
+                Ok(())
             }))
         });
-        // get a token embedding for each command, build a vec of mappings
-        let embeddedings = self.embedding_model.as_ref().unwrap().embed(self.commands.iter()
-            .map(|(_, command)| format!("{}: {}", command.id, command.description)).collect(), None)?;
-        let mut points: Vec<PointStruct> = Vec::new();
-        for (idx, embedding) in embeddedings.into_iter().enumerate() {
-            let (_, command) = self.commands.iter().nth(idx).unwrap();
-            points.push(PointStruct::new(idx as u64 + 1, embedding, Payload::try_from(serde_json::to_value(command)?)?));
+        // only offered when asked for, since anything that can run commands can be talked into it
+        if self.allow_shell {
+            self.commands.insert("shell".into(), Command{
+                id: "shell".into(),
+                description: "Run a shell command and add its output to the conversation as a system message, such as git status.".into(),
+                aliases: vec!["run a command".into()],
+                destructive: false,
+                f: command_fn(|ctx| Box::pin(async move {
+                    println!("// Enter the shell command to run:");
+                    let command = read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?.trim().to_string();
+                    if command.is_empty() {
+                        println!("// No command given");
+                        return Ok(());
+                    }
+                    let shell_command = command.clone();
+                    let (output, failed) = tokio::task::spawn_blocking(move || run_shell(&shell_command)).await??;
+                    let outcome = failed.map(|status| format!(" ({})", status)).unwrap_or_default();
+                    println!("// Added {} bytes of output from '{}'{}", output.len(), command, outcome);
+                    ctx.push_system(format!("Output of `{}`{}:\n```\n{}\n```", command, outcome, output.trim_end()));
+                    Ok(())
+                }))
+            });
         }
-        self.qclient.upsert_points(UpsertPointsBuilder::new(COMMAND_COLL_NAME, points)).await?;
-        Ok(())
     }
-    async fn run_command(&mut self, command: String) -> Result<(), anyhow::Error> {
-        let mut embedding = self.embedding_model.as_ref().unwrap().embed(vec![format!("query: {}", command)], None)?;
-        let first = embedding.pop().unwrap();
-        let response = self.qclient.query(
-            QueryPointsBuilder::new(COMMAND_COLL_NAME).query(first).with_payload(true)
-        ).await?;
-        let id = response.result[0].get("id");
-        let command = match self.commands.get(id.as_str().unwrap()) {
-            Some(command) => {
-                println!("// Executing command '{}': {}", command.id, command.description);
-                command.f.clone()
+    // Replace any commands from a previous load of the file with its current contents, returning
+    // the ids which were added, changed and removed
+    fn load_commands_file(&mut self, path: &Path) -> Result<[Vec<String>; 3], anyhow::Error> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read commands from {}", path.display()))?;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse commands in {}", path.display()))?;
+        // everything is checked before anything is replaced, so a bad edit leaves the old commands
+        let mut loaded: BTreeMap<String, (String, CustomCommand)> = BTreeMap::new();
+        for entry in entries {
+            let source = entry.to_string();
+            let command: CustomCommand = serde_json::from_value(entry)
+                .with_context(|| format!("Failed to parse commands in {}", path.display()))?;
+            if loaded.contains_key(&command.id)
+                || (self.commands.contains_key(&command.id) && !self.custom_commands.contains_key(&command.id)) {
+                return Err(anyhow::Error::msg(format!("Custom command '{}' conflicts with an existing command", command.id)));
             }
-            None => {
-                return Err(anyhow::Error::msg(format!("Command not found: {}", command)));
+            loaded.insert(command.id.clone(), (source, command));
+        }
+        let previous = std::mem::take(&mut self.custom_commands);
+        let mut report = [Vec::new(), Vec::new(), Vec::new()];
+        for id in previous.keys() {
+            self.commands.remove(id);
+            if !loaded.contains_key(id) {
+                report[2].push(id.clone());
             }
-        };
-        (*command)(self)
-    }
-    fn send_context(&mut self) -> Result<(), anyhow::Error> {
-        let response = ureq::post(&self.endpoint)
-            .set("content-type", "application/json")
-            .send_json(&self.context)?
-            .into_json::<ServerResponse>()?;
-        self.context.push(Message::assistant(response.output.clone()));
+        }
+        for (id, (source, command)) in loaded {
+            match previous.get(&id) {
+                None => report[0].push(id.clone()),
+                Some(old) if *old != source => report[1].push(id.clone()),
+                Some(_) => {}
+            }
+            self.commands.insert(id.clone(), command.into_command());
+            self.custom_commands.insert(id, source);
+        }
+        self.commands_file = Some(path.to_path_buf());
+        Ok(report)
+    }
+    // Each persona gets a command of its own so it can be asked for in natural language, along
+    // with a persona command to pick one by name
+    fn load_personas(&mut self, dir: &Path) -> Result<(), anyhow::Error> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read personas from {}", dir.display()))?;
+        let mut personas: BTreeMap<String, String> = BTreeMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !path.is_file() || name.starts_with('.') {
+                continue;
+            }
+            let prompt = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read persona {}", path.display()))?;
+            personas.insert(name.to_string(), prompt.trim_end().to_string());
+        }
+        for (name, prompt) in &personas {
+            let id = format!("persona-{}", name);
+            if self.commands.contains_key(&id) {
+                return Err(anyhow::Error::msg(format!("Persona '{}' conflicts with an existing command", name)));
+            }
+            let prompt = Rc::new(prompt.clone());
+            let label = name.clone();
+            self.commands.insert(id.clone(), Command{
+                id,
+                description: format!("Switch to the {} persona: {}", name, preview(&prompt, 80)),
+                aliases: vec![format!("act as {}", name)],
+                destructive: false,
+                f: command_fn(move |ctx| {
+                    let prompt = prompt.clone();
+                    let label = label.clone();
+                    Box::pin(async move {
+                        ctx.set_system_prompt(prompt.to_string());
+                        println!("// Switched to the {} persona", label);
+                        Ok(())
+                    })
+                }),
+            });
+        }
+        let personas = Rc::new(personas);
+        self.commands.insert("persona".into(), Command{
+            id: "persona".into(),
+            description: "Choose one of the saved personas by name, replacing the system prompt with it.".into(),
+            aliases: vec!["change personality".into()],
+            destructive: false,
+            f: command_fn(move |ctx| {
+                let personas = personas.clone();
+                Box::pin(async move {
+                    println!("// Available personas: {}", personas.keys().cloned().collect::<Vec<_>>().join(", "));
+                    println!("// Enter the persona to switch to:");
+                    let name = read_prompt(&mut ctx.history, &ctx.commands, ctx.input_options)?;
+                    let Some(prompt) = personas.get(name.trim()) else {
+                        return Err(anyhow::Error::msg(format!("there is no persona named '{}'", name.trim())));
+                    };
+                    ctx.set_system_prompt(prompt.clone());
+                    println!("// Switched to the {} persona", name.trim());
+                    Ok(())
+                })
+            }),
+        });
         Ok(())
     }
-    fn send_user_message(&mut self, message: String) -> Result<(), anyhow::Error> {
-        self.context.push(Message::user(message));
-        self.send_context()
+    fn set_system_prompt(&mut self, prompt: String) {
+        self.append_transcript(&Message::system(prompt.clone()));
+        match self.context.first_mut() {
+            Some(Message{role: System, content, ..}) => *content = prompt,
+            _ => self.context.insert(0, Message::system(prompt)),
+        }
     }
-}
-
-#[derive(Serialize)]
-struct Command {
-    id: String,
-    description: String,
-    #[serde(skip)]
-    f: Rc<Box<dyn Fn(&mut ChatContext) -> Result<(), anyhow::Error> + 'static>>
-}
-
-enum InputType {
-    Prompt(String),
-    Command(String),
-}
-
-impl InputType {
-    fn into_string(self) -> String {
-        match self {
-            InputType::Prompt(prompt) => prompt,
-            InputType::Command(cmd) => cmd
+    // Add a message in the system role, logging it like the rest of the conversation
+    fn push_system(&mut self, content: String) {
+        let message = Message::system(content);
+        self.append_transcript(&message);
+        self.context.push(message);
+    }
+    // An exact command id, or a prefix of exactly one
+    fn command_by_name(&self, name: &str) -> Option<String> {
+        if self.commands.contains_key(name) {
+            return Some(name.to_string());
+        }
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        let mut matches = self.commands.keys().filter(|id| id.starts_with(name));
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Some(id.clone()),
+            _ => None,
         }
     }
-}
-
-fn read_message() -> io::Result<InputType> {
-    let mut line = String::new();
-    while let Event::Key(KeyEvent { code, modifiers, .. }) = event::read()? {
-        match code {
-            KeyCode::Enter => {
-                if modifiers.contains(KeyModifiers::ALT) {
-                    break;
-                }
-                line.push('\n');
-            }
-            KeyCode::Char(c) => {
-                line.push(c);
+    // Find the command semantically closest to the input
+    async fn match_command(&mut self, command: &str) -> Result<String, anyhow::Error> {
+        let ranked = self.rank_commands(command).await?;
+        let Some(&(_, best)) = ranked.first() else {
+            return Err(anyhow::Error::msg(format!("No command matched '{}'", command)));
+        };
+        if !self.distance.matches(best, self.command_threshold) {
+            return Err(anyhow::Error::msg(format!("No command matched '{}' (best score {:.2})", command, best)));
+        }
+        let mut candidates: Vec<(String, f32)> = ranked.into_iter()
+            .take_while(|(_, score)| self.distance.matches(*score, self.command_threshold)
+                && (score - best).abs() <= self.disambiguation_delta)
+            .collect();
+        if candidates.len() == 1 {
+            return Ok(candidates.remove(0).0);
+        }
+        self.choose_command(candidates)
+    }
+    // Every command Qdrant returns for the input with its score, best first
+    async fn rank_commands(&mut self, command: &str) -> Result<Vec<(String, f32)>, anyhow::Error> {
+        // exact command names have already been tried by now, and keep working without Qdrant
+        self.ensure_commands_ready().await
+            .map_err(|err| anyhow::Error::msg(format!("command matching unavailable, only exact command names work: {:#}", err)))?;
+        let (Some(embedding_model), Some(qclient)) = (&self.embedding_model, &self.qclient) else {
+            return Err(anyhow::Error::msg("command matching unavailable: Qdrant not connected"));
+        };
+        let started = Instant::now();
+        let mut embedding = embedding_model.embed(vec![format!("{}{}", QUERY_PREFIX, command)], Some(self.embed_batch_size))?;
+        let first = embedding.pop()
+            .ok_or_else(|| anyhow::Error::msg("embedding model returned no embedding for the command"))?;
+        self.log(format!("embedded query into {} dimensions in {:?}", first.len(), started.elapsed()));
+        let started = Instant::now();
+        let response = match qclient.query(
+            QueryPointsBuilder::new(self.collection_name.as_str()).query(first).with_payload(true)
+        ).await {
+            Ok(response) => response,
+            Err(err) => {
+                // reconnect on the next attempt, in case Qdrant went away mid-session
+                self.qclient = None;
+                return Err(anyhow::Error::msg(format!("command matching unavailable: Qdrant query failed: {}", err)));
             }
-            _ => {
-                println!("Unknown key {:?}", code);
+        };
+        self.log(format!("qdrant returned {} results in {:?}", response.result.len(), started.elapsed()));
+        for point in &response.result {
+            self.log(format!("  {:.4} {}", point.score, point.get("id").as_str().map(String::as_str).unwrap_or("?")));
+        }
+        let mut ranked: Vec<(String, f32)> = Vec::new();
+        for point in &response.result {
+            match point.get("id").as_str() {
+                // aliases mean one command can have several points in the results
+                Some(id) if ranked.iter().any(|(seen, _)| seen == id) => {}
+                Some(id) => ranked.push((id.clone(), point.score)),
+                None => return Err(anyhow::Error::msg("matched point missing id field")),
             }
         }
+        Ok(ranked)
     }
-    if line.starts_with('/') {
-        Ok(InputType::Command(line[1..].to_string()))
-    } else {
-        Ok(InputType::Prompt(line))
+    // Ask which of several similarly scored commands was meant, defaulting to the best
+    fn choose_command(&mut self, candidates: Vec<(String, f32)>) -> Result<String, anyhow::Error> {
+        println!("// Several commands matched closely:");
+        for (idx, (id, score)) in candidates.iter().enumerate() {
+            let description = self.commands.get(id).map(|c| c.description.as_str()).unwrap_or("");
+            println!("//   {}. {} ({:.2}): {}", idx + 1, id, score, description);
+        }
+        println!("// Enter a number to run, or nothing for 1");
+        let choice = read_prompt(&mut self.history, &self.commands, self.input_options)?;
+        let choice = choice.trim();
+        if choice.is_empty() {
+            return Ok(candidates[0].0.clone());
+        }
+        match choice.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= candidates.len() => Ok(candidates[n - 1].0.clone()),
+            _ => Err(anyhow::Error::msg(format!("'{}' is not one of the listed commands", choice))),
+        }
     }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    role: Role,
-    content: String,
-}
-
-impl Message {
-    fn user(content: String) -> Self {
-        Self{
-            role: Role::User,
-            content,
+    // Load the embedding model and connect to Qdrant the first time a command is run, so sessions
+    // which never use commands don't pay for either
+    async fn ensure_commands_ready(&mut self) -> Result<(), anyhow::Error> {
+        if self.embedding_model.is_some() && self.qclient.is_some() {
+            return Ok(());
+        }
+        let dimension = TextEmbedding::get_model_info(&self.embedding_model_kind)?.dim as u64;
+        // the model is kept even if Qdrant is down, so a later attempt only has to reconnect
+        if self.embedding_model.is_none() {
+            let started = Instant::now();
+            let model = match self.preloading.take() {
+                Some(preloading) => preloading.recv()
+                    .unwrap_or_else(|_| Err(anyhow::Error::msg("the background load of the embedding model stopped"))),
+                None => self.load_embedding_model(),
+            };
+            self.embedding_model = Some(model?);
+            self.log(format!("loaded {:?} ({} dimensions) in {:?}", self.embedding_model_kind, dimension, started.elapsed()));
+        }
+        let started = Instant::now();
+        let qclient = self.connect_qdrant().await?;
+        self.log(format!("connected to qdrant at {} in {:?}", self.qdrant_url, started.elapsed()));
+        self.create_collection(&qclient, dimension).await?;
+        // an existing collection may have been created for a different model
+        let info = qclient.collection_info(self.collection_name.as_str()).await?;
+        let existing = info.result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.vectors_config)
+            .and_then(|vectors| vectors.config);
+        if let Some(vectors_config::Config::Params(params)) = existing {
+            let mismatch = if params.size != dimension {
+                Some(format!("The '{}' collection holds {} dimensional vectors, but {:?} produces {}",
+                    self.collection_name, params.size, self.embedding_model_kind, dimension))
+            } else if params.distance != Distance::from(self.distance) as i32 {
+                Some(format!("The '{}' collection was created with a different distance metric than {:?}",
+                    self.collection_name, self.distance))
+            } else {
+                None
+            };
+            if let Some(mismatch) = mismatch {
+                if !self.recreate_collection {
+                    return Err(anyhow::Error::msg(format!(
+                        "{}; rerun with --recreate-collection or delete the collection", mismatch)));
+                }
+                println!("// {}, recreating it", mismatch);
+                qclient.delete_collection(self.collection_name.as_str()).await?;
+                self.create_collection(&qclient, dimension).await?;
+            }
+        }
+        self.qclient = Some(qclient);
+        let started = Instant::now();
+        if let Err(err) = self.sync_command_points().await {
+            self.qclient = None;
+            return Err(err);
         }
+        self.log(format!("synced command points in {:?}", started.elapsed()));
+        Ok(())
     }
-    fn system(content: String) -> Self {
-        Self{
-            role: Role::System,
-            content,
+    // diagnostics for --verbose, kept on stderr so they can be separated from the conversation
+    fn log(&self, message: String) {
+        if self.verbose {
+            eprintln!("// [verbose] {}", message);
         }
     }
-    fn assistant(content: String) -> Self {
-        Self{
-            role: Role::Assistant,
-            content,
+    fn load_embedding_model(&self) -> Result<TextEmbedding, anyhow::Error> {
+        load_embedding_model(&self.embedding_model_kind, &self.model_cache, false)
+    }
+    // Begin loading the model on a thread of its own, for ensure_commands_ready to pick up later.
+    // With --verbose the load is then logged with only the time spent waiting on it. Loading in
+    // the background draws no download progress, which would land on top of the input. The thread
+    // is detached rather than on the blocking pool, which the runtime would wait on when exiting
+    fn preload_embedding_model(&mut self) {
+        let kind = self.embedding_model_kind.clone();
+        let cache = self.model_cache.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(load_embedding_model(&kind, &cache, true));
+        });
+        self.preloading = Some(receiver);
+    }
+    async fn connect_qdrant(&self) -> Result<Qdrant, anyhow::Error> {
+        let qclient = Qdrant::from_url(&self.qdrant_url).build()
+            .context("Failed to build Qdrant vector db client")?;
+        qclient.health_check().await
+            .with_context(|| format!("Unable to connect to Qdrant at {}", self.qdrant_url))?;
+        Ok(qclient)
+    }
+    // Try each service the session depends on, printing a report and failing if any are down
+    async fn check(&self) -> Result<(), anyhow::Error> {
+        let llm = probe_server(self.agent.clone(), self.endpoint.clone()).await;
+        let qdrant = self.connect_qdrant().await.map(|_| ());
+        let model = self.load_embedding_model().map(|_| ());
+        let results = [
+            (format!("LLM server at {}", self.endpoint), llm),
+            (format!("Qdrant at {}", self.qdrant_url), qdrant),
+            (format!("Embedding model {:?}", self.embedding_model_kind), model),
+        ];
+        let mut failed = 0;
+        for (name, result) in &results {
+            match result {
+                Ok(()) => println!("// {}: ok", name),
+                Err(err) => {
+                    failed += 1;
+                    println!("// {}: FAILED: {:#}", name, err);
+                }
+            }
         }
+        if failed > 0 {
+            return Err(anyhow::Error::msg(format!("{} of {} checks failed", failed, results.len())));
+        }
+        Ok(())
     }
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(rename_all = "snake_case")]
+    // create the command collection, leaving an existing one in place
+    async fn create_collection(&self, qclient: &Qdrant, dimension: u64) -> Result<(), anyhow::Error> {
+        match qclient.create_collection(
+            CreateCollectionBuilder::new(self.collection_name.as_str())
+                .vectors_config(VectorParamsBuilder::new(dimension, self.distance.into()))).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // qdrant versions differ in how they report a collection that already exists, so
+                // rather than trust the error code, ask whether it is actually there
+                match qclient.collection_exists(self.collection_name.as_str()).await {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(anyhow::Error::new(e)
+                        .context(format!("Failed to create collection {}", self.collection_name))),
+                    Err(check) => Err(anyhow::Error::new(e).context(format!(
+                        "Failed to create collection {}, and could not check whether it exists: {}",
+                        self.collection_name, check))),
+                }
+            }
+        }
+    }
+    // Only embed commands which are new or whose text has changed since the points were last written
+    async fn sync_command_points(&self) -> Result<(), anyhow::Error> {
+        let qclient = self.qclient.as_ref().unwrap();
+        let mut stored: HashMap<u64, String> = HashMap::new();
+        let mut offset: Option<PointId> = None;
+        loop {
+            let mut scroll = ScrollPointsBuilder::new(self.collection_name.as_str())
+                .limit(256)
+                .with_payload(true)
+                .with_vectors(false);
+            if let Some(offset) = offset.take() {
+                scroll = scroll.offset(offset);
+            }
+            let page = qclient.scroll(scroll).await?;
+            for point in page.result {
+                if let Some(PointId{point_id_options: Some(PointIdOptions::Num(id))}) = point.id {
+                    stored.insert(id, point.get("hash").as_str().cloned().unwrap_or_default());
+                }
+            }
+            match page.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+        }
+        let wanted: Vec<(&Command, u64, String)> = self.commands.values()
+            .flat_map(|command| command.points().into_iter().map(move |(id, text)| (command, id, text)))
+            .collect();
+        let changed: Vec<&(&Command, u64, String)> = wanted.iter()
+            .filter(|(_, id, text)| stored.get(id) != Some(&text_hash(text)))
+            .collect();
+        self.log(format!("{} stored points, {} to embed", stored.len(), changed.len()));
+        if !changed.is_empty() {
+            // get a token embedding for each changed point, build a vec of mappings
+            let embeddings = self.embedding_model.as_ref().unwrap().embed(changed.iter()
+                .map(|(_, _, text)| text.clone()).collect(), Some(self.embed_batch_size))?;
+            let mut points: Vec<PointStruct> = Vec::new();
+            for ((command, id, text), embedding) in changed.into_iter().zip(embeddings) {
+                let mut payload = Payload::try_from(serde_json::to_value(command)?)?;
+                payload.insert("hash", text_hash(text));
+                points.push(PointStruct::new(*id, embedding, payload));
+            }
+            qclient.upsert_points(UpsertPointsBuilder::new(self.collection_name.as_str(), points)).await?;
+        }
+        let removed: Vec<PointId> = stored.keys()
+            .filter(|id| !wanted.iter().any(|(_, wanted_id, _)| wanted_id == *id))
+            .map(|id| PointId::from(*id))
+            .collect();
+        if !removed.is_empty() {
+            self.log(format!("removing {} stale points", removed.len()));
+            qclient.delete_points(DeletePointsBuilder::new(self.collection_name.as_str())
+                .points(PointsIdsList{ids: removed})).await?;
+        }
+        Ok(())
+    }
+    async fn run_command(&mut self, command: String) -> Result<ControlFlow<()>, anyhow::Error> {
+        if !self.commands_enabled {
+            return Err(anyhow::Error::msg("commands are disabled by --no-commands"));
+        }
+        // known command names skip the embedding model and Qdrant entirely
+        let id = match self.command_by_name(command.trim()) {
+            Some(id) => {
+                self.log(format!("'{}' matched command '{}' by name", command.trim(), id));
+                id
+            }
+            None => self.match_command(&command).await?,
+        };
+        let command = match self.commands.get(&id) {
+            Some(command) => {
+                if command.destructive && !confirm(&format!("Run '{}'? It discards part of the conversation", command.id))? {
+                    println!("// Not running '{}'", command.id);
+                    return Ok(ControlFlow::Continue(()));
+                }
+                println!("// Executing command '{}': {}", command.id, command.description);
+                command.f.clone()
+            }
+            None => {
+                return Err(anyhow::Error::msg(format!("Command not found: {}", command)));
+            }
+        };
+        let before = self.snapshot();
+        let result = (*command)(self).await;
+        // commands which change the conversation are saved as replies are, failed ones included
+        // since they may have rolled it back
+        if self.context != before {
+            self.autosave();
+        }
+        result?;
+        Ok(if self.quitting { ControlFlow::Break(()) } else { ControlFlow::Continue(()) })
+    }
+    // Sends the context to the server and prints the reply, streaming it if the server supports it
+    async fn send_context(&mut self) -> Result<(), anyhow::Error> {
+        self.send_context_with(self.sampling).await
+    }
+    // As send_context, but with sampling settings for just this request
+    async fn send_context_with(&mut self, sampling: SamplingParams) -> Result<(), anyhow::Error> {
+        let reply = self.request_reply(sampling).await?;
+        self.accept_reply(reply);
+        Ok(())
+    }
+    // As send_context, but logging the message that was sent ahead of the reply, once the request
+    // has gone through, so a failed request leaves nothing in the log
+    async fn send_context_after(&mut self, sent: &Message) -> Result<(), anyhow::Error> {
+        let reply = self.request_reply(self.sampling).await?;
+        self.append_transcript(sent);
+        self.accept_reply(reply);
+        Ok(())
+    }
+    // Record a reply in the stats, log, context and recovery file
+    fn accept_reply(&mut self, reply: Option<Reply>) {
+        let Some(reply) = reply else {
+            return;
+        };
+        self.stats.replies += 1;
+        self.stats.chars_sent += self.context.iter().map(|message| message.content.chars().count()).sum::<usize>();
+        self.stats.chars_received += reply.received;
+        if let Some(time) = reply.time {
+            self.stats.generation_secs += time;
+            self.stats.timed_replies += 1;
+        }
+        self.append_transcript(&reply.message);
+        self.context.push(reply.message);
+        self.autosave();
+    }
+    // Send the context and show the reply, leaving the context, stats, log and recovery file for
+    // the caller to update. Nothing comes back with --dry-run
+    async fn request_reply(&mut self, sampling: SamplingParams) -> Result<Option<Reply>, anyhow::Error> {
+        let endpoint = self.endpoint.clone();
+        // a connection left idle too long may have been dropped by the server or a proxy
+        if let (Some(idle), Some(last)) = (self.idle_reconnect, self.last_request) {
+            if last.elapsed() >= idle {
+                self.log(format!("idle for {:?}, reconnecting", last.elapsed()));
+                self.agent = build_agent(self.timeout, self.insecure)?;
+            }
+        }
+        let agent = self.agent.clone();
+        let headers = self.headers.clone();
+        let timeout = self.timeout;
+        let max_retries = self.max_retries;
+        // a json line can only be written once the whole response is in
+        let stream = self.stream && self.output_format == OutputFormat::Text;
+        let stream_flush = self.stream_flush;
+        let prefix = self.reply_prefix();
+        let backend = self.backend;
+        let messages = if self.merge_system { Cow::Owned(merge_system_messages(&self.context)) } else { Cow::Borrowed(&self.context[..]) };
+        let body = backend.request_body(self.model.as_deref(), &messages, stream, sampling)?;
+        if self.dry_run {
+            println!("// POST {}", endpoint);
+            println!("{}", serde_json::to_string_pretty(&body)?);
+            return Ok(None);
+        }
+        let spinner = Spinner::start();
+        let request_spinner = spinner.clone();
+        // ureq blocks, so run it on the blocking pool rather than stalling the runtime
+        // streamed output has already been printed by the time the request finishes
+        let result = tokio::task::spawn_blocking(move || -> Result<(String, Option<f32>, bool), anyhow::Error> {
+            let spinner = request_spinner;
+            let response = post_with_retries(&agent, &endpoint, &headers, &body, stream, max_retries, &spinner)
+                .map_err(|e| describe_request_error(&endpoint, timeout, *e))?;
+            // servers without streaming support just answer with a regular json body
+            let framing = match response.content_type() {
+                "text/event-stream" => Some(StreamFraming::ServerSentEvents),
+                "application/x-ndjson" => Some(StreamFraming::JsonLines),
+                _ => None,
+            };
+            if let Some(framing) = framing {
+                let (output, time) = read_stream(response, backend, framing, stream_flush, prefix, &spinner,
+                    |err| describe_read_error(&endpoint, timeout, err))?;
+                Ok((output, time, true))
+            } else {
+                // ureq reports text/plain when there is no header at all, so check for one first
+                let content_type = response.header("content-type").unwrap_or_default().to_ascii_lowercase();
+                let raw = response.into_string().map_err(|err| describe_read_error(&endpoint, timeout, err))?;
+                // minimal servers may answer with the text itself rather than json
+                if content_type.starts_with("text/") {
+                    return Ok((raw, None, false));
+                }
+                let (output, time) = serde_json::from_str(&raw)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|json| backend.parse_response(json))
+                    .map_err(|e| anyhow::Error::msg(format!("server returned unexpected format ({}): {}", e, raw)))?;
+                Ok((output, time, false))
+            }
+        }).await;
+        spinner.stop();
+        self.last_request = Some(Instant::now());
+        let (output, time, streamed) = result??;
+        let received = output.chars().count();
+        let (output, thinking) = if self.hide_thinking { split_thinking(&output) } else { (output, None) };
+        // an empty turn would only confuse the model on the next request, so keep it out of the context
+        if output.trim().is_empty() {
+            return Err(anyhow::Error::msg("server returned an empty response"));
+        }
+        if self.output_format == OutputFormat::Json {
+            println!("{}", serde_json::json!({"role": Role::Assistant, "content": output, "time": time}));
+        } else if !streamed {
+            self.display_response(&output);
+        }
+        if let (true, Some(time), OutputFormat::Text) = (self.show_timings, time, self.output_format) {
+            println!("// generated in {:.2}s", time);
+        }
+        let mut message = Message::assistant(output);
+        message.thinking = thinking;
+        Ok(Some(Reply{message, time, received}))
+    }
+    // Like autosave this only warns, a transcript line going missing shouldn't end the session
+    fn append_transcript(&self, message: &Message) {
+        let Some(path) = &self.log_file else {
+            return;
+        };
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let line = serde_json::json!({
+            "role": message.role.as_str(),
+            "content": message.content,
+            "timestamp": timestamp,
+        });
+        // a single write per line, so a crash can only ever cut off the last entry
+        let result = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut file| file.write_all(format!("{}\n", line).as_bytes()));
+        if let Err(err) = result {
+            println!("// Warning: failed to append to log file {}: {}", path.display(), err);
+        }
+    }
+    // A copy of the conversation as it stands, to be handed back to restore later
+    fn snapshot(&self) -> Vec<Message> {
+        self.context.clone()
+    }
+    fn restore(&mut self, messages: Vec<Message>) {
+        self.context = messages;
+    }
+    // Failing to autosave shouldn't interrupt the conversation, so this only warns
+    fn autosave(&self) {
+        let Some(path) = &self.recovery_file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.context)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| write_atomic(path, json.as_bytes()));
+        if let Err(err) = result {
+            println!("// Warning: failed to write recovery file {}: {}", path.display(), err);
+        }
+    }
+    // labels responses so they stand apart from input and command output when scrolling back
+    fn reply_prefix(&self) -> Option<String> {
+        self.color.then(|| "assistant>".green().bold().to_string())
+    }
+    fn display_response(&self, output: &str) {
+        if let Some(prefix) = self.reply_prefix() {
+            println!("{}", prefix);
+        }
+        let mut output = output.to_string();
+        if let (true, Ok((width, _))) = (self.wrap, terminal::size()) {
+            output = wrap_text(&output, width as usize);
+        }
+        // escape codes would just be noise in a pipe or file
+        if self.render_markdown && io::stdout().is_terminal() {
+            output = render_markdown(&output);
+        }
+        println!("{}", output);
+    }
+    async fn send_user_message(&mut self, message: String) -> Result<(), anyhow::Error> {
+        let original = self.snapshot();
+        let message = Message::user(message);
+        self.context.push(message.clone());
+        self.trim_context();
+        let result = self.send_context_after(&message).await;
+        if result.is_err() {
+            // leave the context as it was, rather than with a message that was never answered
+            self.restore(original);
+        } else {
+            self.stats.user_turns += 1;
+            self.consecutive_retries = 0;
+        }
+        result
+    }
+    // Drop the oldest exchanges past the system prompt until the context fits within
+    // max_context_messages, returning how many messages were dropped
+    fn trim_context(&mut self) -> usize {
+        let Some(limit) = self.max_context_messages else {
+            return 0;
+        };
+        let mut dropped = 0;
+        while self.context.len() > limit.max(1) + 1 {
+            // a user message goes together with the reply that followed it
+            let count = match (self.context.get(1), self.context.get(2)) {
+                (Some(first), Some(second)) if matches!(first.role, Role::User)
+                    && matches!(second.role, Role::Assistant) => 2,
+                _ => 1,
+            };
+            if self.context.len() - count < 2 {
+                break;
+            }
+            self.context.drain(1..1 + count);
+            dropped += count;
+        }
+        dropped
+    }
+}
+
+#[derive(Serialize)]
+struct Command {
+    id: String,
+    description: String,
+    // other phrasings of the command, each embedded as a point of its own
+    aliases: Vec<String>,
+    // asks for confirmation before running, as the command discards part of the conversation
+    #[serde(skip)]
+    destructive: bool,
+    #[serde(skip)]
+    f: CommandFn
+}
+
+impl Command {
+    // The points this command is matched by, as (point id, embedded text) pairs. Point ids need
+    // to be stable across runs, so they are derived from the command id and alias
+    fn points(&self) -> Vec<(u64, String)> {
+        let mut points = vec![(fnv1a(self.id.as_bytes()), format!("{}{}: {}", PASSAGE_PREFIX, self.id, self.description))];
+        for alias in &self.aliases {
+            points.push((fnv1a(format!("{}\0{}", self.id, alias).as_bytes()), format!("{}{}", PASSAGE_PREFIX, alias)));
+        }
+        points
+    }
+}
+
+// Changes whenever the embedded text does, so stale points can be found without comparing vectors
+fn text_hash(text: &str) -> String {
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
+
+// std's hashers aren't guaranteed stable between releases, which matters for anything persisted
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// A command defined in the --commands-file rather than built in
+#[derive(Deserialize, Debug)]
+struct CustomCommand {
+    id: String,
+    description: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    destructive: bool,
+    #[serde(flatten)]
+    action: CustomAction,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum CustomAction {
+    // Added to the context as a system message
+    System(String),
+    // Sent as a user message, with any {input} replaced by text entered when the command runs
+    Prompt(String),
+}
+
+impl CustomCommand {
+    fn into_command(self) -> Command {
+        let action = Rc::new(self.action);
+        Command{
+            id: self.id,
+            description: self.description,
+            aliases: self.aliases,
+            destructive: self.destructive,
+            f: command_fn(move |ctx| {
+                let action = action.clone();
+                Box::pin(async move {
+                    match &*action {
+                        CustomAction::System(content) => {
+                            ctx.push_system(content.clone());
+                            Ok(())
+                        }
+                        CustomAction::Prompt(template) => {
+                            let prompt = if template.contains("{input}") {
+                                println!("// Enter the input for this command below:");
+                                template.replace("{input}", &read_text(&mut ctx.history, &ctx.commands, ctx.input_options)?)
+                            } else {
+                                template.clone()
+                            };
+                            // @path works here just as it does in a message typed into the chat
+                            ctx.send_user_message(include_files(&prompt)?).await
+                        }
+                    }
+                })
+            }),
+        }
+    }
+}
+
+enum InputType {
+    Prompt(String),
+    Command(String),
+    // Ctrl+C with something typed, discarding it
+    Cancelled,
+    // Ctrl+C on an empty line
+    Exit,
+}
+
+impl InputType {
+    fn into_string(self) -> String {
+        match self {
+            InputType::Prompt(prompt) => prompt,
+            InputType::Command(cmd) => cmd,
+            InputType::Cancelled | InputType::Exit => String::new(),
+        }
+    }
+}
+
+// Keys like Ctrl+C only reach us as events in raw mode, which has to be undone however input ends.
+// Bracketed paste is switched on alongside it so pasted text arrives as a single event
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let _ = crossterm::execute!(io::stdout(), event::EnableBracketedPaste);
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(io::stdout(), event::DisableBracketedPaste);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Soft and hard caps on the characters in a single input, and which key sends it
+#[derive(Clone, Copy, Debug)]
+struct InputOptions {
+    warn: usize,
+    max: usize,
+    submit: SubmitKey,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self{warn: 50_000, max: 1_000_000, submit: SubmitKey::AltEnter}
+    }
+}
+
+impl From<&Config> for InputOptions {
+    fn from(config: &Config) -> Self {
+        Self{warn: config.input_warn_chars, max: config.input_max_chars, submit: config.submit_key}
+    }
+}
+
+// Ask a yes or no question answered with a single key, anything but y meaning no
+fn confirm(question: &str) -> io::Result<bool> {
+    println!("// {} (y/N)", question);
+    let _raw = RawMode::enable()?;
+    loop {
+        if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+            return Ok(matches!(code, KeyCode::Char('y') | KeyCode::Char('Y')));
+        }
+    }
+}
+
+// Read a message that must be a plain prompt, as used for input inside of commands. Tab completes
+// command names, as the answer is usually a setting, path or choice rather than free text
+fn read_prompt(history: &mut Vec<String>, commands: &BTreeMap<String, Command>, options: InputOptions) -> Result<String, anyhow::Error> {
+    read_command_input(history, commands, options, true)
+}
+
+// As read_prompt, for input which becomes a message, shell command or request body, where Tab
+// is typed as it is
+fn read_text(history: &mut Vec<String>, commands: &BTreeMap<String, Command>, options: InputOptions) -> Result<String, anyhow::Error> {
+    read_command_input(history, commands, options, false)
+}
+
+fn read_command_input(history: &mut Vec<String>, commands: &BTreeMap<String, Command>, options: InputOptions, complete: bool) -> Result<String, anyhow::Error> {
+    match read_message(history, commands, options, complete)? {
+        InputType::Prompt(prompt) => Ok(prompt),
+        InputType::Command(_) => {
+            Err(anyhow::Error::msg("unable to process command input inside command shell"))
+        }
+        InputType::Cancelled | InputType::Exit => Err(anyhow::Error::msg("input cancelled")),
+    }
+}
+
+// In command input, a line starting with / or anything read with in_command set, Tab completes the
+// word before the cursor against the ids in commands, and elsewhere it types a tab. Input stops
+// growing at the hard limit, and going past the soft one asks for confirmation before it is sent
+fn read_message(history: &mut Vec<String>, commands: &BTreeMap<String, Command>, options: InputOptions, in_command: bool) -> io::Result<InputType> {
+    let raw = RawMode::enable()?;
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    // byte offset into line where typing is inserted, always on a char boundary
+    let mut cursor = 0;
+    // while browsing history, the position being shown and whatever was typed before browsing began
+    let mut position: Option<usize> = None;
+    let mut draft = String::new();
+    // while tab is pressed repeatedly, what was typed before completing and the match being shown
+    let mut completing: Option<(String, usize)> = None;
+    let mut rows_above = 0;
+    let cancelled = loop {
+        rows_above = render_input(&mut stdout, &line, cursor, rows_above)?;
+        let (code, modifiers) = match event::read()? {
+            Event::Key(KeyEvent { code, modifiers, .. }) => (code, modifiers),
+            Event::Paste(text) => {
+                // pasted newlines are kept as-is instead of being treated as Enter presses
+                let mut text = text.replace("\r\n", "\n").replace('\r', "\n");
+                let room = options.max.saturating_sub(line.chars().count());
+                if let Some((idx, _)) = text.char_indices().nth(room) {
+                    text.truncate(idx);
+                    queue!(stdout, Print("\x07"))?;
+                }
+                line.insert_str(cursor, &text);
+                cursor += text.len();
+                completing = None;
+                continue;
+            }
+            Event::Resize(cols, _) => {
+                // the terminal rewraps the input already on screen, so work out which of the new
+                // rows the cursor is on before redrawing it at the new width
+                let width = (cols as usize).max(1);
+                rows_above = if cursor < line.len() { input_position(&line[..cursor], width).0 } else { input_rows(&line, width) } as u16;
+                continue;
+            }
+            _ => continue,
+        };
+        if code != KeyCode::Tab {
+            completing = None;
+        }
+        let full = line.chars().count() >= options.max;
+        match code {
+            KeyCode::Enter => {
+                let alt = modifiers.contains(KeyModifiers::ALT);
+                if alt == (options.submit == SubmitKey::AltEnter) {
+                    break None;
+                }
+                if full {
+                    queue!(stdout, Print("\x07"))?;
+                    continue;
+                }
+                line.insert(cursor, '\n');
+                cursor += 1;
+            }
+            KeyCode::Backspace => {
+                if let Some((idx, _)) = line[..cursor].char_indices().next_back() {
+                    line.remove(idx);
+                    cursor = idx;
+                }
+            }
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                break Some(if line.is_empty() { InputType::Exit } else { InputType::Cancelled });
+            }
+            KeyCode::Char('w') if modifiers.contains(KeyModifiers::CONTROL) => {
+                let mut head = line[..cursor].to_string();
+                delete_word(&mut head);
+                line.replace_range(..cursor, &head);
+                cursor = head.len();
+            }
+            KeyCode::Left => {
+                cursor = line[..cursor].char_indices().next_back().map(|(idx, _)| idx).unwrap_or(0);
+            }
+            KeyCode::Right => {
+                cursor += line[cursor..].chars().next().map(char::len_utf8).unwrap_or(0);
+            }
+            // home and end move within the current line of a multiline input
+            KeyCode::Home => {
+                cursor = line[..cursor].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+            }
+            KeyCode::End => {
+                cursor += line[cursor..].find('\n').unwrap_or(line.len() - cursor);
+            }
+            KeyCode::Up => {
+                let previous = match position {
+                    None if !history.is_empty() => {
+                        draft = std::mem::take(&mut line);
+                        Some(history.len() - 1)
+                    }
+                    Some(idx) if idx > 0 => Some(idx - 1),
+                    other => other,
+                };
+                if let Some(idx) = previous {
+                    line = history[idx].clone();
+                }
+                position = previous;
+                cursor = line.len();
+            }
+            KeyCode::Down => {
+                if let Some(idx) = position {
+                    if idx + 1 < history.len() {
+                        line = history[idx + 1].clone();
+                        position = Some(idx + 1);
+                    } else {
+                        line = std::mem::take(&mut draft);
+                        position = None;
+                    }
+                }
+                cursor = line.len();
+            }
+            // control combinations without a binding would otherwise type their bare letter
+            KeyCode::Char(_) if modifiers.contains(KeyModifiers::CONTROL) => {}
+            KeyCode::Char(_) if full => {
+                queue!(stdout, Print("\x07"))?;
+            }
+            KeyCode::Char(c) => {
+                line.insert(cursor, c);
+                cursor += c.len_utf8();
+            }
+            KeyCode::Tab => {
+                let start = line[..cursor].rfind(|c: char| c.is_whitespace() || c == '/')
+                    .map(|idx| idx + line[idx..].chars().next().unwrap().len_utf8())
+                    .unwrap_or(0);
+                let (prefix, next) = match completing.take() {
+                    Some((prefix, idx)) => (prefix, idx + 1),
+                    None => (line[start..cursor].to_string(), 0),
+                };
+                // with nothing typed yet every command would match, so Tab is just a tab there
+                let matches: Vec<&String> = if !prefix.is_empty() && (in_command || line.starts_with('/')) {
+                    commands.keys().filter(|id| id.starts_with(&prefix)).collect()
+                } else {
+                    Vec::new()
+                };
+                if !matches.is_empty() {
+                    let idx = next % matches.len();
+                    line.replace_range(start..cursor, matches[idx]);
+                    cursor = start + matches[idx].len();
+                    completing = Some((prefix, idx));
+                } else if !full {
+                    line.insert(cursor, '\t');
+                    cursor += 1;
+                }
+            }
+            // function keys with no binding are ignored rather than echoed into the input
+            _ => {}
+        }
+    };
+    // the cursor may be mid-input, so draw once more with it at the end
+    render_input(&mut stdout, &line, line.len(), rows_above)?;
+    // leave the finished input on screen and start output on a fresh line
+    queue!(stdout, Print("\r\n"))?;
+    stdout.flush()?;
+    drop(raw);
+    if let Some(input) = cancelled {
+        return Ok(input);
+    }
+    if !line.is_empty() {
+        history.push(line.clone());
+    }
+    let length = line.chars().count();
+    // the message stays in the history, so declining only costs pressing up to get it back
+    if length > options.warn && !confirm(&format!("This message is {} characters long, send it anyway?", length))? {
+        return Ok(InputType::Cancelled);
+    }
+    if let Some(cmd) = line.strip_prefix('/') {
+        Ok(InputType::Command(cmd.to_string()))
+    } else {
+        Ok(InputType::Prompt(line))
+    }
+}
+
+// Redraw the input over the previous render, returning how many rows sit above the cursor
+// afterward so the next render knows how far back up to start
+fn render_input(stdout: &mut io::Stdout, line: &str, cursor: usize, rows_above: u16) -> io::Result<u16> {
+    let width = terminal::size().map(|(cols, _)| cols as usize).unwrap_or(80).max(1);
+    // MoveUp(0) still moves a row on most terminals
+    if rows_above > 0 {
+        queue!(stdout, cursor::MoveUp(rows_above))?;
+    }
+    queue!(stdout, cursor::MoveToColumn(0), terminal::Clear(ClearType::FromCursorDown))?;
+    for (idx, text) in line.split('\n').enumerate() {
+        let prefix = if idx == 0 { INPUT_PROMPT } else { INPUT_CONTINUATION };
+        if idx > 0 {
+            queue!(stdout, Print("\r\n"))?;
+        }
+        queue!(stdout, Print(prefix), Print(expand_tabs(text)))?;
+    }
+    let mut rows = input_rows(line, width);
+    if cursor < line.len() {
+        let (row, column) = input_position(&line[..cursor], width);
+        if rows > row {
+            queue!(stdout, cursor::MoveUp((rows - row) as u16))?;
+        }
+        queue!(stdout, cursor::MoveToColumn(column as u16))?;
+        rows = row;
+    }
+    stdout.flush()?;
+    Ok(rows as u16)
+}
+
+// Tabs are drawn as a fixed run of spaces so the width of the input stays known
+fn expand_tabs(text: &str) -> Cow<'_, str> {
+    if text.contains('\t') { Cow::Owned(text.replace('\t', "    ")) } else { Cow::Borrowed(text) }
+}
+
+// The row the end of the input is drawn on, counted from its first row
+fn input_rows(line: &str, width: usize) -> usize {
+    let lines: Vec<&str> = line.split('\n').collect();
+    let mut rows = 0;
+    for (idx, text) in lines.iter().enumerate() {
+        let prefix = if idx == 0 { INPUT_PROMPT } else { INPUT_CONTINUATION };
+        let len = prefix.width() + expand_tabs(text).width();
+        if idx + 1 < lines.len() {
+            rows += len.max(1).div_ceil(width);
+        } else {
+            // the cursor stays on the last row even when the text exactly fills it
+            rows += len.saturating_sub(1) / width;
+        }
+    }
+    rows
+}
+
+// The row and column the character after the given input lands on, wrapped the same way as
+// render_input lays it out
+fn input_position(before: &str, width: usize) -> (usize, usize) {
+    let lines: Vec<&str> = before.split('\n').collect();
+    let mut row = 0;
+    let mut column = 0;
+    for (idx, text) in lines.iter().enumerate() {
+        let prefix = if idx == 0 { INPUT_PROMPT } else { INPUT_CONTINUATION };
+        let len = prefix.width() + expand_tabs(text).width();
+        if idx + 1 < lines.len() {
+            row += len.max(1).div_ceil(width);
+        } else {
+            row += len / width;
+            column = len % width;
+        }
+    }
+    (row, column)
+}
+
+// Remove the previous whitespace-delimited word, along with any whitespace trailing it
+fn delete_word(line: &mut String) {
+    let trimmed = line.trim_end_matches(char::is_whitespace);
+    let start = trimmed.rfind(char::is_whitespace)
+        .map(|idx| idx + trimmed[idx..].chars().next().unwrap().len_utf8())
+        .unwrap_or(0);
+    line.truncate(start);
+}
+
+// Insert the flags from a --profile ahead of the given arguments. Profiles live in a json
+// object keyed by name, each holding option names and values, e.g. {"work": {"llm_host": "..."}}
+fn with_profile(args: Vec<String>) -> Result<Vec<String>, anyhow::Error> {
+    let name = args.iter().enumerate().find_map(|(idx, arg)| match arg.strip_prefix("--profile=") {
+        Some(name) => Some(name.to_string()),
+        None if arg == "--profile" => args.get(idx + 1).cloned(),
+        None => None,
+    });
+    let Some(name) = name else {
+        return Ok(args);
+    };
+    let path = profiles_path()?;
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read profiles from {}", path.display()))?;
+    let mut profiles: HashMap<String, serde_json::Map<String, serde_json::Value>> = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse profiles in {}", path.display()))?;
+    let profile = profiles.remove(&name)
+        .ok_or_else(|| anyhow::Error::msg(format!("No profile named '{}' in {}", name, path.display())))?;
+    merge_profile(args, &name, profile)
+}
+
+// Put a profile's settings ahead of the given arguments, leaving out any the arguments set themselves
+fn merge_profile(args: Vec<String>, name: &str, profile: serde_json::Map<String, serde_json::Value>) -> Result<Vec<String>, anyhow::Error> {
+    let command = <Config as clap::CommandFactory>::command();
+    let mut merged = args[..1].to_vec();
+    for (key, value) in profile {
+        let long = key.replace('_', "-");
+        let flag = format!("--{}", long);
+        // repeatable flags would otherwise add to the profile's values rather than replace them
+        let short = command.get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .and_then(|arg| arg.get_short())
+            .map(|short| format!("-{}", short));
+        let given = args[1..].iter().any(|arg| {
+            arg == &flag || arg.starts_with(&format!("{}=", flag))
+                || short.as_ref().is_some_and(|short| arg.starts_with(short.as_str()) && !arg.starts_with("--"))
+        });
+        if given {
+            continue;
+        }
+        let values = match value {
+            serde_json::Value::Bool(true) => {
+                merged.push(flag);
+                continue;
+            }
+            serde_json::Value::Bool(false) | serde_json::Value::Null => continue,
+            serde_json::Value::Array(items) => items,
+            serde_json::Value::Object(_) => {
+                return Err(anyhow::Error::msg(format!("Profile '{}' has an object for '{}', expected a value", name, key)));
+            }
+            value => vec![value],
+        };
+        for value in values {
+            merged.push(flag.clone());
+            merged.push(value.as_str().map(String::from).unwrap_or_else(|| value.to_string()));
+        }
+    }
+    merged.extend_from_slice(&args[1..]);
+    Ok(merged)
+}
+
+fn profiles_path() -> Result<PathBuf, anyhow::Error> {
+    dirs::config_dir()
+        .map(|dir| dir.join("qmuloai").join("config.json"))
+        .ok_or_else(|| anyhow::Error::msg("unable to find the config directory for profiles"))
+}
+
+// Write the sampling settings into a profile, leaving its other settings and the other profiles alone
+fn save_profile_sampling(name: &str, sampling: SamplingParams) -> Result<PathBuf, anyhow::Error> {
+    let path = profiles_path()?;
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read profiles from {}", path.display()))?;
+    let mut profiles: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse profiles in {}", path.display()))?;
+    let profile = profiles.get_mut(name).and_then(|profile| profile.as_object_mut())
+        .ok_or_else(|| anyhow::Error::msg(format!("No profile named '{}' in {}", name, path.display())))?;
+    // going through the f32's display form keeps 0.7 from being written as 0.699999988
+    let float = |value: f32| serde_json::Value::from(value.to_string().parse::<f64>().unwrap_or(value as f64));
+    let settings = [
+        ("temperature", sampling.temperature.map(float)),
+        ("top_p", sampling.top_p.map(float)),
+        ("max_tokens", sampling.max_tokens.map(serde_json::Value::from)),
+    ];
+    for (key, value) in settings {
+        // profiles accept both field and flag names, so replace either spelling with the flag one
+        let flag = key.replace('_', "-");
+        profile.remove(key);
+        match value {
+            Some(value) => profile.insert(flag, value),
+            None => profile.remove(&flag),
+        };
+    }
+    write_atomic(&path, serde_json::to_string_pretty(&profiles)?.as_bytes())?;
+    Ok(path)
+}
+
+fn describe_param<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| "server default".into())
+}
+
+// An empty answer keeps the current value and 'none' clears it
+fn parse_param<T: std::str::FromStr>(input: &str, name: &str, current: Option<T>) -> Result<Option<T>, anyhow::Error> {
+    match input.trim() {
+        "" => Ok(current),
+        value if value.eq_ignore_ascii_case("none") => Ok(None),
+        value => value.parse().map(Some)
+            .map_err(|_| anyhow::Error::msg(format!("'{}' is not a valid {}", value, name))),
+    }
+}
+
+// Saved conversations are either just the messages or, when there are notes, an object of both
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedConversation {
+    Messages(Vec<Message>),
+    WithNotes {
+        messages: Vec<Message>,
+        #[serde(default)]
+        notes: Vec<String>,
+    },
+}
+
+struct Conversation {
+    messages: Vec<Message>,
+    notes: Vec<String>,
+}
+
+fn read_conversation(path: &Path) -> Result<Conversation, anyhow::Error> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read conversation from {}", path.display()))?;
+    let saved = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse conversation in {}", path.display()))?;
+    Ok(match saved {
+        SavedConversation::Messages(messages) => Conversation{messages, notes: Vec::new()},
+        SavedConversation::WithNotes{messages, notes} => Conversation{messages, notes},
+    })
+}
+
+fn model_cache_path(config: &Config) -> Result<PathBuf, anyhow::Error> {
+    match &config.model_cache {
+        Some(dir) => Ok(expand_tilde(dir)),
+        None => dirs::cache_dir()
+            .map(|dir| dir.join("qmuloai").join("models"))
+            .ok_or_else(|| anyhow::Error::msg("unable to find a cache directory for embedding models, pass --model-cache")),
+    }
+}
+
+fn load_embedding_model(kind: &EmbeddingModel, cache: &Path, background: bool) -> Result<TextEmbedding, anyhow::Error> {
+    std::fs::create_dir_all(cache)
+        .with_context(|| format!("Failed to create the model cache {}", cache.display()))?;
+    let options = InitOptions::new(kind.clone())
+        .with_show_download_progress(!background)
+        .with_cache_dir(cache.to_path_buf());
+    // fastembed panics rather than erroring when a model file can't be fetched, so catch that
+    // quietly and report it the same way. Only this thread's panics are silenced, see quiet_panics
+    QUIET_PANICS.with(|quiet| quiet.set(true));
+    let loaded = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| TextEmbedding::try_new(options)));
+    QUIET_PANICS.with(|quiet| quiet.set(false));
+    let loaded = loaded.unwrap_or_else(|panic| {
+        let reason = panic.downcast_ref::<String>().cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|reason| reason.to_string()))
+            .unwrap_or_else(|| "the model loader panicked".to_string());
+        Err(anyhow::Error::msg(reason.trim().to_string()))
+    });
+    loaded.map_err(|err| anyhow::Error::msg(format!(
+        "Failed to load embedding model {:?} from {}: {:#}. Check that the --model-cache directory \
+        exists and is writable, and on first use that the model can be downloaded",
+        kind, cache.display(), err)))
+}
+
+thread_local! {
+    // set while a thread runs code whose panics are caught and reported as errors instead
+    static QUIET_PANICS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// Installed once at startup, so silencing a panic never means swapping the process wide hook
+// while other threads may be panicking
+fn quiet_panics() {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !QUIET_PANICS.with(|quiet| quiet.get()) {
+            hook(info);
+        }
+    }));
+}
+
+fn recovery_path(config: &Config) -> Option<PathBuf> {
+    match &config.recovery_file {
+        Some(file) => Some(expand_tilde(file)),
+        None => dirs::data_local_dir().map(|dir| dir.join("qmuloai").join(format!("recovery-{}.json", std::process::id()))),
+    }
+}
+
+fn recovery_lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+// Lock a recovery file for as long as the returned handle lives, or None if a running session
+// already holds it
+fn lock_recovery(path: &Path) -> Result<Option<std::fs::File>, anyhow::Error> {
+    let lock_path = recovery_lock_path(path);
+    if let Some(parent) = lock_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .with_context(|| format!("Failed to open recovery lock {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+        Err(std::fs::TryLockError::Error(err)) => Err(anyhow::Error::from(err)
+            .context(format!("Failed to lock {}", lock_path.display()))),
+    }
+}
+
+fn remove_recovery(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(recovery_lock_path(path));
+}
+
+// Recovery files which may have been left behind by sessions that didn't exit cleanly, the most
+// recent first. An explicit file is only ever this session's, the default directory holds one
+// file per session
+fn leftover_recoveries(config: &Config, path: &Path) -> Vec<PathBuf> {
+    if config.recovery_file.is_some() {
+        return if path.exists() { vec![path.to_path_buf()] } else { Vec::new() };
+    }
+    let Some(entries) = path.parent().and_then(|dir| std::fs::read_dir(dir).ok()) else {
+        return Vec::new();
+    };
+    let mut leftovers = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("recovery-") && name.ends_with(".json") && entry.path() != path {
+            let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+            leftovers.push((modified, entry.path()));
+        }
+    }
+    leftovers.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    leftovers.into_iter().map(|(_, path)| path).collect()
+}
+
+// Claim this session's recovery file, offering to resume any session which didn't exit cleanly.
+// Returns the claimed file with the lock which keeps other sessions away from it, or None if a
+// running session already uses it, along with the messages of a resumed session
+#[allow(clippy::type_complexity)]
+fn claim_recovery(config: &Config) -> Result<(Option<(PathBuf, std::fs::File)>, Option<Vec<Message>>), anyhow::Error> {
+    let Some(path) = recovery_path(config) else {
+        return Ok((None, None));
+    };
+    let Some(lock) = lock_recovery(&path)? else {
+        println!("// Warning: another session is using the recovery file {}, this one won't be saved", path.display());
+        return Ok((None, None));
+    };
+    for leftover in leftover_recoveries(config, &path) {
+        let own = leftover == path;
+        // a session still holding its lock is running, not abandoned
+        let held = if own { None } else {
+            match lock_recovery(&leftover)? {
+                Some(held) => Some(held),
+                None => continue,
+            }
+        };
+        let recovered = offer_recovery(&leftover)?;
+        if !own {
+            // resumed sessions carry on in this session's file, declined ones are discarded
+            if recovered.is_some() {
+                std::fs::rename(&leftover, &path)
+                    .with_context(|| format!("Failed to move {} to {}", leftover.display(), path.display()))?;
+            }
+            remove_recovery(&leftover);
+            drop(held);
+        }
+        if recovered.is_some() {
+            return Ok((Some((path, lock)), recovered));
+        }
+    }
+    Ok((Some((path, lock)), None))
+}
+
+// Write to a temporary file first and rename it over the target, so a crash mid-write can't
+// leave a truncated file behind
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Offer to pick up a session which didn't exit cleanly, returning its messages if accepted
+fn offer_recovery(path: &Path) -> Result<Option<Vec<Message>>, anyhow::Error> {
+    let messages: Vec<Message> = match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(serde_json::from_str(&json)?)) {
+        Ok(messages) => messages,
+        Err(err) => {
+            println!("// Ignoring unreadable recovery file {}: {}", path.display(), err);
+            return Ok(None);
+        }
+    };
+    let question = format!("Found an unfinished session with {} messages in {}, resume it?", messages.len(), path.display());
+    Ok(confirm(&question)?.then_some(messages))
+}
+
+fn read_system_prompt(config: &Config) -> Result<Option<String>, anyhow::Error> {
+    if config.system_prompt_file.is_empty() {
+        println!("Enter the system prompt for this session below: ");
+        loop {
+            match read_message(&mut Vec::new(), &BTreeMap::new(), config.into(), false)? {
+                InputType::Exit => return Ok(None),
+                // as in the chat, Ctrl+C only discards what was typed
+                InputType::Cancelled => println!("// Cancelled, press Ctrl+C again to exit"),
+                input => return Ok(Some(input.into_string())),
+            }
+        }
+    }
+    let mut prompts = Vec::new();
+    for file in &config.system_prompt_file {
+        let path = expand_tilde(file);
+        let mut prompt = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read system prompt from {}", path.display()))?;
+        // drop only the file's final newline, anything beyond that is intentional
+        if prompt.ends_with('\n') {
+            prompt.pop();
+            if prompt.ends_with('\r') {
+                prompt.pop();
+            }
+        }
+        prompts.push(prompt);
+    }
+    Ok(Some(prompts.join("\n\n")))
+}
+
+// A line diff from the longest common subsequence of lines, marking each line ' ' when it is in
+// both, '-' when only in old and '+' when only in new
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<(char, &'a str)> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // lengths[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            diff.push(('-', old[i]));
+            i += 1;
+        } else {
+            diff.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| ('-', *line)));
+    diff.extend(new[j..].iter().map(|line| ('+', *line)));
+    diff
+}
+
+// Replace each @path word naming an existing file with the file's contents in a fenced block, so
+// code can be asked about without pasting it. Any other @word, such as a decorator or a handle, is
+// sent as written, and @@ sends a literal @ even when a file of that name exists
+fn include_files(prompt: &str) -> Result<String, anyhow::Error> {
+    let mut expanded = String::new();
+    for piece in prompt.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end_matches(char::is_whitespace);
+        let spacing = &piece[word.len()..];
+        if let Some(literal) = word.strip_prefix("@@") {
+            expanded.push('@');
+            expanded.push_str(literal);
+            expanded.push_str(spacing);
+            continue;
+        }
+        // punctuation after a path is part of the sentence, not the file name
+        let file = word.strip_prefix('@')
+            .map(|file| file.trim_end_matches([',', '.', ';', ':', '!', '?', ')', ']', '"', '\'']))
+            .unwrap_or("");
+        let path = expand_tilde(file);
+        if file.is_empty() || !path.is_file() {
+            expanded.push_str(piece);
+            continue;
+        }
+        let size = std::fs::metadata(&path)
+            .with_context(|| format!("Unable to include @{}", file))?
+            .len();
+        if size > MAX_INCLUDE_BYTES {
+            return Err(anyhow::Error::msg(format!(
+                "@{} is {} bytes, larger than the {} byte limit for included files, write @@ to send it as text",
+                file, size, MAX_INCLUDE_BYTES)));
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to include @{}, write @@ to send it as text", file))?;
+        let language = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        expanded.push_str(&format!("\n\n{}:\n```{}\n{}\n```\n", file, language, contents.trim_end()));
+        expanded.push_str(&word[1 + file.len()..]);
+        expanded.push_str(spacing);
+    }
+    Ok(expanded)
+}
+
+// Prepend each system message to the next user message, or send it as one if none follows
+fn merge_system_messages(messages: &[Message]) -> Vec<Message> {
+    let mut merged = Vec::new();
+    let mut pending: Vec<&str> = Vec::new();
+    for message in messages {
+        match message.role {
+            Role::System => pending.push(&message.content),
+            Role::User if !pending.is_empty() => {
+                pending.push(&message.content);
+                merged.push(Message::user(pending.join("\n\n")));
+                pending.clear();
+            }
+            _ => merged.push(message.clone()),
+        }
+    }
+    if !pending.is_empty() {
+        merged.push(Message::user(pending.join("\n\n")));
+    }
+    merged
+}
+
+// Separate <think> sections from a reply, returning the answer and the reasoning if there was
+// any. An unclosed section runs to the end of the reply, as when generation is cut off
+fn split_thinking(output: &str) -> (String, Option<String>) {
+    let mut answer = String::new();
+    let mut thinking: Vec<&str> = Vec::new();
+    let mut rest = output;
+    while let Some(start) = rest.find("<think>") {
+        answer.push_str(&rest[..start]);
+        let inner = &rest[start + "<think>".len()..];
+        match inner.find("</think>") {
+            Some(end) => {
+                thinking.push(inner[..end].trim());
+                rest = &inner[end + "</think>".len()..];
+            }
+            None => {
+                thinking.push(inner.trim());
+                rest = "";
+            }
+        }
+    }
+    answer.push_str(rest);
+    let thinking = if thinking.is_empty() { None } else { Some(thinking.join("\n\n")) };
+    (answer.trim().to_string(), thinking)
+}
+
+// Squash a message onto one line, cut down to at most width characters
+fn preview(content: &str, width: usize) -> String {
+    let flattened = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if flattened.chars().count() <= width {
+        return flattened;
+    }
+    let mut truncated: String = flattened.chars().take(width.saturating_sub(3)).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+// Greedily wrap prose at word boundaries, but leave fenced code blocks alone since rewrapping
+// would break their formatting
+fn wrap_text(text: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut wrapped = String::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            wrapped.push_str(line);
+            wrapped.push('\n');
+            continue;
+        }
+        if in_code || line.width() <= width {
+            wrapped.push_str(line);
+            wrapped.push('\n');
+            continue;
+        }
+        // indentation is kept, and carried over onto the lines the wrapping adds
+        let rest = line.trim_start();
+        let indent = &line[..line.len() - rest.len()];
+        let available = width.saturating_sub(indent.width()).max(1);
+        wrapped.push_str(indent);
+        let mut current = 0;
+        for word in rest.split(' ') {
+            let word_width = word.width();
+            if current > 0 && current + 1 + word_width > available {
+                wrapped.push('\n');
+                wrapped.push_str(indent);
+                current = 0;
+            } else if current > 0 {
+                wrapped.push(' ');
+                current += 1;
+            }
+            wrapped.push_str(word);
+            current += word_width;
+        }
+        wrapped.push('\n');
+    }
+    // match the input, which may or may not have ended in a newline
+    if !text.ends_with('\n') {
+        wrapped.pop();
+    }
+    wrapped
+}
+
+// A deliberately small subset of markdown: bold, inline code and fenced code blocks
+fn render_markdown(text: &str) -> String {
+    let mut rendered = String::new();
+    let mut in_code = false;
+    for line in text.split('\n') {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            rendered.push_str(&line.dim().to_string());
+        } else if in_code {
+            rendered.push_str(&line.with(Color::Cyan).to_string());
+        } else {
+            rendered.push_str(&render_inline_markdown(line));
+        }
+        rendered.push('\n');
+    }
+    rendered.pop();
+    rendered
+}
+
+fn render_inline_markdown(line: &str) -> String {
+    let mut rendered = String::new();
+    let mut rest = line;
+    loop {
+        let marker = [rest.find("**").map(|pos| (pos, "**")), rest.find('`').map(|pos| (pos, "`"))]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(pos, _)| *pos);
+        let Some((start, marker)) = marker else {
+            rendered.push_str(rest);
+            return rendered;
+        };
+        let inner_start = start + marker.len();
+        match rest[inner_start..].find(marker) {
+            Some(len) => {
+                let inner = &rest[inner_start..inner_start + len];
+                rendered.push_str(&rest[..start]);
+                if marker == "**" {
+                    rendered.push_str(&inner.bold().to_string());
+                } else {
+                    rendered.push_str(&inner.dim().to_string());
+                }
+                rest = &rest[inner_start + len + marker.len()..];
+            }
+            // an unclosed marker is just text
+            None => {
+                rendered.push_str(&rest[..inner_start]);
+                rest = &rest[inner_start..];
+            }
+        }
+    }
+}
+
+// Render a transcript, with system messages quoted so they stand apart from the conversation itself
+fn conversation_markdown(messages: &[Message], notes: &[String]) -> String {
+    let mut markdown = String::new();
+    for (idx, message) in messages.iter().enumerate() {
+        match message.role {
+            Role::System => {
+                markdown.push_str(if idx == 0 { "### System Prompt\n\n" } else { "### System\n\n" });
+                for line in message.content.lines() {
+                    markdown.push_str("> ");
+                    markdown.push_str(line);
+                    markdown.push('\n');
+                }
+            }
+            Role::User => {
+                markdown.push_str("### User\n\n");
+                markdown.push_str(&message.content);
+                markdown.push('\n');
+            }
+            Role::Assistant => {
+                markdown.push_str("### Assistant\n\n");
+                if let Some(thinking) = &message.thinking {
+                    markdown.push_str("<details><summary>Thinking</summary>\n\n");
+                    markdown.push_str(thinking);
+                    markdown.push_str("\n\n</details>\n\n");
+                }
+                markdown.push_str(&message.content);
+                markdown.push('\n');
+            }
+        }
+        markdown.push('\n');
+    }
+    if !notes.is_empty() {
+        markdown.push_str("### Notes\n\n");
+        for note in notes {
+            markdown.push_str("- ");
+            markdown.push_str(&note.replace('\n', "\n  "));
+            markdown.push('\n');
+        }
+    }
+    markdown
+}
+
+// Hand the text to whichever clipboard tool the platform has
+fn copy_to_clipboard(text: &str) -> Result<(), anyhow::Error> {
+    const TOOLS: [(&str, &[&str]); 5] = [
+        ("pbcopy", &[]),
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+        ("xsel", &["--clipboard", "--input"]),
+        ("clip", &[]),
+    ];
+    for (program, args) in TOOLS {
+        let Ok(mut child) = std::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn() else {
+            continue;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        if child.wait()?.success() {
+            return Ok(());
+        }
+    }
+    Err(anyhow::Error::msg("no clipboard is available, install one of pbcopy, wl-copy, xclip or xsel"))
+}
+
+// Run the command through sh, returning its output with stderr interleaved, and its exit status
+// if it failed, since a failing build or diff is often what's worth showing. Output past the size
+// limit is dropped and the command stopped, as it is if it runs past the timeout
+fn run_shell(command: &str) -> Result<(String, Option<std::process::ExitStatus>), anyhow::Error> {
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", &format!("exec 2>&1\n{}", command)])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", command))?;
+    let deadline = Instant::now() + SHELL_TIMEOUT;
+    let mut stdout = child.stdout.take().unwrap();
+    // reading blocks until the command closes its output, so it's done apart from the timeout
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let read = stdout.by_ref().take(MAX_SHELL_OUTPUT_BYTES as u64 + 1).read_to_end(&mut output);
+        let _ = sender.send(read.map(|_| output));
+    });
+    let timed_out = |child: &mut std::process::Child| {
+        let _ = child.kill();
+        let _ = child.wait();
+        anyhow::Error::msg(format!("'{}' didn't finish within {}s", command, SHELL_TIMEOUT.as_secs()))
+    };
+    let mut output = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(output) => output?,
+        Err(_) => return Err(timed_out(&mut child)),
+    };
+    let truncated = output.len() > MAX_SHELL_OUTPUT_BYTES;
+    if truncated {
+        output.truncate(MAX_SHELL_OUTPUT_BYTES);
+        let _ = child.kill();
+    }
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            return Err(timed_out(&mut child));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    if truncated {
+        output.push_str(&format!("\n(output cut off at {} bytes)", MAX_SHELL_OUTPUT_BYTES));
+    }
+    // stopping it after too much output isn't the command failing
+    let failed = (!status.success() && !truncated).then_some(status);
+    Ok((output, failed))
+}
+
+// Accepts either the fastembed variant name or the model's code, ignoring case
+fn parse_embedding_model(name: &str) -> Result<EmbeddingModel, String> {
+    let models = TextEmbedding::list_supported_models();
+    models.iter()
+        .find(|info| format!("{:?}", info.model).eq_ignore_ascii_case(name) || info.model_code.eq_ignore_ascii_case(name))
+        .map(|info| info.model.clone())
+        .ok_or_else(|| {
+            let mut names: Vec<String> = models.iter().map(|info| format!("{:?}", info.model)).collect();
+            names.sort();
+            format!("unknown embedding model, expected one of: {}", names.join(", "))
+        })
+}
+
+// Split a "Name: value" header argument
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => Err("expected a header in the form \"Name: value\"".to_string()),
+    }
+}
+
+// Join a host with the generation path, checking the result is a usable url up front rather
+// than on the first message
+fn endpoint_url(host: &str, path: &str) -> Result<String, anyhow::Error> {
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    let endpoint = format!("{}{}", with_scheme(host).trim_end_matches('/'), path);
+    let url = url::Url::parse(&endpoint)
+        .with_context(|| format!("'{}' is not a valid LLM endpoint", endpoint))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(anyhow::Error::msg(format!("LLM endpoint '{}' must use http or https", endpoint)));
+    }
+    Ok(endpoint)
+}
+
+// Allow hosts to be given without a scheme, defaulting to plain http
+fn with_scheme(url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    }
+}
+
+// PathBuf does no shell expansion, so a leading ~ has to be resolved by hand
+fn expand_tilde(path: &str) -> PathBuf {
+    if path == "~" {
+        if let Some(home) = dirs::home_dir() {
+            return home;
+        }
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest);
+        }
+    }
+    PathBuf::from(path)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Message {
+    role: Role,
+    content: String,
+    // reasoning stripped from an assistant reply by --hide-thinking, never sent to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+}
+
+// A message as sent to the server, with only the fields backends understand
+#[derive(Serialize)]
+struct WireMessage<'a> {
+    role: &'a Role,
+    content: &'a str,
+}
+
+impl Message {
+    fn user(content: String) -> Self {
+        Self{
+            role: Role::User,
+            content,
+            thinking: None,
+        }
+    }
+    fn system(content: String) -> Self {
+        Self{
+            role: Role::System,
+            content,
+            thinking: None,
+        }
+    }
+    fn assistant(content: String) -> Self {
+        Self{
+            role: Role::Assistant,
+            content,
+            thinking: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
 enum Role {
     User,
     System,
     Assistant
 }
 
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::System => "system",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    /// The Qmulo /generate endpoint
+    Qmulo,
+    /// OpenAI compatible /v1/chat/completions servers, like llama.cpp or vLLM
+    Openai,
+    /// Ollama's native /api/chat endpoint
+    Ollama,
+}
+
+impl Backend {
+    fn path(self) -> &'static str {
+        match self {
+            Backend::Qmulo => "/generate",
+            Backend::Openai => "/v1/chat/completions",
+            Backend::Ollama => "/api/chat",
+        }
+    }
+    fn request_body(self, model: Option<&str>, messages: &[Message], stream: bool, sampling: SamplingParams) -> Result<serde_json::Value, anyhow::Error> {
+        let messages: Vec<WireMessage> = messages.iter()
+            .map(|message| WireMessage{role: &message.role, content: &message.content})
+            .collect();
+        let mut body = match self {
+            // the original protocol is a bare list of messages, so only wrap it when there is more to send
+            Backend::Qmulo if sampling.is_empty() => return Ok(serde_json::to_value(messages)?),
+            Backend::Qmulo => serde_json::json!({
+                "messages": messages,
+            }),
+            Backend::Openai => {
+                let mut body = serde_json::json!({
+                    "messages": messages,
+                    "stream": stream,
+                });
+                if let Some(model) = model {
+                    body["model"] = model.into();
+                }
+                body
+            }
+            // ollama streams unless told otherwise, and takes sampling settings under its own names
+            Backend::Ollama => {
+                let mut body = serde_json::json!({
+                    "messages": messages,
+                    "stream": stream,
+                    "options": {},
+                });
+                if let Some(model) = model {
+                    body["model"] = model.into();
+                }
+                if let Some(temperature) = sampling.temperature {
+                    body["options"]["temperature"] = temperature.into();
+                }
+                if let Some(top_p) = sampling.top_p {
+                    body["options"]["top_p"] = top_p.into();
+                }
+                if let Some(max_tokens) = sampling.max_tokens {
+                    body["options"]["num_predict"] = max_tokens.into();
+                }
+                return Ok(body);
+            }
+        };
+        if let serde_json::Value::Object(params) = serde_json::to_value(sampling)? {
+            body.as_object_mut().unwrap().extend(params);
+        }
+        Ok(body)
+    }
+    // Pull the reply, and the generation time if the backend reports one, out of a complete response
+    fn parse_response(self, body: serde_json::Value) -> Result<(String, Option<f32>), anyhow::Error> {
+        match self {
+            Backend::Qmulo => {
+                let response: ServerResponse = serde_json::from_value(body)?;
+                Ok((response.output, response.time))
+            }
+            Backend::Openai => {
+                let mut response: OpenAiResponse = serde_json::from_value(body)?;
+                if response.choices.is_empty() {
+                    return Err(anyhow::Error::msg("server response contained no choices"));
+                }
+                Ok((response.choices.swap_remove(0).message.content, None))
+            }
+            Backend::Ollama => {
+                let response: OllamaResponse = serde_json::from_value(body)?;
+                Ok(response.into_parts())
+            }
+        }
+    }
+    // Same as parse_response, but for a single event of a streamed response
+    fn parse_event(self, data: &str) -> Result<(String, Option<f32>), serde_json::Error> {
+        match self {
+            Backend::Qmulo => {
+                let chunk: StreamChunk = serde_json::from_str(data)?;
+                Ok((chunk.output, chunk.time))
+            }
+            Backend::Openai => {
+                let chunk: OpenAiChunk = serde_json::from_str(data)?;
+                let content = chunk.choices.into_iter().next()
+                    .and_then(|choice| choice.delta.content)
+                    .unwrap_or_default();
+                Ok((content, None))
+            }
+            Backend::Ollama => {
+                let chunk: OllamaResponse = serde_json::from_str(data)?;
+                Ok(chunk.into_parts())
+            }
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => {
+                // crossterm checks NO_COLOR itself, which always should override
+                crossterm::style::force_color_output(true);
+                true
+            }
+            ColorChoice::Never => false,
+            ColorChoice::Auto => io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum SubmitKey {
+    Enter,
+    AltEnter,
+}
+
+impl SubmitKey {
+    fn hint(self) -> &'static str {
+        match self {
+            SubmitKey::Enter => "Enter sends a message and Alt+Enter starts a new line",
+            SubmitKey::AltEnter => "Alt+Enter sends a message and Enter starts a new line",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum DistanceMetric {
+    Cosine,
+    Dot,
+    Euclid,
+}
+
+impl DistanceMetric {
+    // Equivalent cutoffs for normalized embeddings, a Euclidean distance of 1 is a cosine of 0.5
+    fn default_threshold(self) -> f32 {
+        match self {
+            DistanceMetric::Cosine | DistanceMetric::Dot => 0.5,
+            DistanceMetric::Euclid => 1.0,
+        }
+    }
+    // Qdrant scores Euclid by distance, so lower is better there and higher is better otherwise
+    fn matches(self, score: f32, threshold: f32) -> bool {
+        match self {
+            DistanceMetric::Cosine | DistanceMetric::Dot => score >= threshold,
+            DistanceMetric::Euclid => score <= threshold,
+        }
+    }
+}
+
+impl From<DistanceMetric> for Distance {
+    fn from(metric: DistanceMetric) -> Self {
+        match metric {
+            DistanceMetric::Cosine => Distance::Cosine,
+            DistanceMetric::Dot => Distance::Dot,
+            DistanceMetric::Euclid => Distance::Euclid,
+        }
+    }
+}
+
+// A response from the LLM server, not yet added to the context
+struct Reply {
+    message: Message,
+    time: Option<f32>,
+    received: usize,
+}
+
+// Generation settings sent along with the messages, any left unset are omitted
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+impl SamplingParams {
+    fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.top_p.is_none() && self.max_tokens.is_none()
+    }
+}
+
+// Running totals for the stats command, covering every request made this session
+#[derive(Clone, Copy, Debug, Default)]
+struct SessionStats {
+    user_turns: usize,
+    replies: usize,
+    chars_sent: usize,
+    chars_received: usize,
+    // only servers which report a generation time contribute to this
+    generation_secs: f32,
+    timed_replies: usize,
+}
+
+// Proxies in front of qmulo servers don't always keep its field names, so common ones are accepted
 #[derive(Deserialize, Serialize, Debug)]
 struct ServerResponse {
+    #[serde(alias = "response", alias = "text", alias = "generated_text")]
+    output: String,
+    #[serde(default)]
+    time: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiResponse {
+    choices: Vec<OpenAiChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChoice {
+    message: Message,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChunk {
+    choices: Vec<OpenAiChunkChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChunkChoice {
+    delta: OpenAiDelta,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiDelta {
+    content: Option<String>,
+}
+
+// Ollama uses the same shape for a full response and each line of a streamed one
+#[derive(Deserialize, Debug)]
+struct OllamaResponse {
+    message: Option<OllamaMessage>,
+    // in nanoseconds, only present once generation is done
+    total_duration: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaMessage {
+    content: String,
+}
+
+impl OllamaResponse {
+    fn into_parts(self) -> (String, Option<f32>) {
+        let content = self.message.map(|message| message.content).unwrap_or_default();
+        (content, self.total_duration.map(|ns| ns as f32 / 1e9))
+    }
+}
+
+// Retries server errors and refused connections, which are usually a model still loading, but
+// not 4xx responses since those will only fail the same way again
+fn post_with_retries(agent: &ureq::Agent, endpoint: &str, headers: &[(String, String)], body: &serde_json::Value, stream: bool, max_retries: u32, spinner: &Spinner) -> Result<ureq::Response, Box<ureq::Error>> {
+    let mut attempt = 0;
+    loop {
+        let mut request = agent.post(endpoint)
+            .set("content-type", "application/json");
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if stream {
+            request = request.set("accept", "text/event-stream");
+        }
+        let err = match request.send_json(body) {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        let retryable = match &err {
+            ureq::Error::Status(code, _) => *code >= 500,
+            ureq::Error::Transport(transport) => transport.kind() == ureq::ErrorKind::ConnectionFailed,
+        };
+        if !retryable || attempt >= max_retries {
+            return Err(Box::new(err));
+        }
+        attempt += 1;
+        spinner.println(&format!("// retrying ({}/{})...", attempt, max_retries));
+        std::thread::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1)));
+    }
+}
+
+fn build_agent(timeout: Duration, insecure: bool) -> Result<ureq::Agent, anyhow::Error> {
+    // per read rather than overall, so a slow stream that keeps sending isn't cut off
+    let mut builder = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout);
+    if insecure {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to set up TLS without certificate verification")?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+            .with_no_client_auth();
+        builder = builder.tls_config(Arc::new(config));
+    }
+    Ok(builder.build())
+}
+
+// For --insecure: any certificate is accepted, though the handshake is still checked to be signed
+// by whichever certificate the server presented
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Any http response, even an error status, shows something is listening at the url
+async fn probe_server(agent: ureq::Agent, url: String) -> Result<(), anyhow::Error> {
+    let probe = tokio::task::spawn_blocking(move || {
+        match agent.get(&url).timeout(Duration::from_secs(5)).call() {
+            Err(ureq::Error::Transport(transport)) => Err(transport.to_string()),
+            _ => Ok(()),
+        }
+    }).await?;
+    probe.map_err(anyhow::Error::msg)
+}
+
+// Turn a failed request into an error saying whether the server is down, slow, or rejecting it
+fn describe_request_error(endpoint: &str, timeout: Duration, err: ureq::Error) -> anyhow::Error {
+    if is_timeout(&err) {
+        return anyhow::Error::msg(format!("No response from {} after {}s", endpoint, timeout.as_secs()));
+    }
+    match err {
+        ureq::Error::Status(code, response) => {
+            let body = response.into_string().unwrap_or_default();
+            anyhow::Error::msg(format!("LLM server returned HTTP {}: {}", code, body))
+        }
+        ureq::Error::Transport(transport) => {
+            let reason = transport.to_string();
+            let hint = if reason.to_ascii_lowercase().contains("certificate") {
+                ", pass --insecure if it uses a self-signed certificate"
+            } else {
+                ""
+            };
+            anyhow::Error::msg(format!("LLM server at {} is unreachable: {}{}", endpoint, reason, hint))
+        }
+    }
+}
+
+// Animates a line while waiting on the server. Drawing happens under the lock so stopping it
+// from another thread can't race with a frame being drawn
+#[derive(Clone)]
+struct Spinner {
+    active: Arc<Mutex<bool>>,
+}
+
+impl Spinner {
+    fn start() -> Self {
+        let spinner = Self{active: Arc::new(Mutex::new(io::stdout().is_terminal()))};
+        let active = spinner.active.clone();
+        std::thread::spawn(move || {
+            for frame in ['|', '/', '-', '\\'].iter().cycle() {
+                {
+                    let active = active.lock().unwrap();
+                    if !*active {
+                        break;
+                    }
+                    print!("\r// thinking {}", frame);
+                    let _ = io::stdout().flush();
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+        spinner
+    }
+    // Clear the spinner off the line, every call after the first does nothing
+    fn stop(&self) {
+        let mut active = self.active.lock().unwrap();
+        if *active {
+            *active = false;
+            let _ = crossterm::execute!(io::stdout(), cursor::MoveToColumn(0), terminal::Clear(ClearType::CurrentLine));
+        }
+    }
+    // Print a line of its own without it being mixed into the spinner
+    fn println(&self, line: &str) {
+        let active = self.active.lock().unwrap();
+        if *active {
+            let _ = crossterm::execute!(io::stdout(), cursor::MoveToColumn(0), terminal::Clear(ClearType::CurrentLine));
+        }
+        println!("{}", line);
+    }
+}
+
+fn is_timeout(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Transport(transport) => std::error::Error::source(transport)
+            .and_then(|source| source.downcast_ref::<io::Error>())
+            .map(|e| matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// As describe_request_error, for a response body which fails partway through
+fn describe_read_error(endpoint: &str, timeout: Duration, err: io::Error) -> anyhow::Error {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            anyhow::Error::msg(format!("No response from {} after {}s", endpoint, timeout.as_secs()))
+        }
+        _ => anyhow::Error::msg(format!("LLM server at {} is unreachable: {}", endpoint, err)),
+    }
+}
+
+// How the events of a streamed response are separated
+#[derive(Clone, Copy, Debug)]
+enum StreamFraming {
+    // data: lines, as used by Qmulo and OpenAI style servers
+    ServerSentEvents,
+    // a json object per line, as used by Ollama
+    JsonLines,
+}
+
+// A single server-sent event from a streamed generation
+#[derive(Deserialize, Debug)]
+struct StreamChunk {
+    #[serde(default, alias = "response", alias = "text", alias = "generated_text")]
     output: String,
-    time: f32,
+    // only expected on the final event
+    time: Option<f32>,
+}
+
+// Print each event of a streamed response as it arrives, returning the full output and
+// generation time if the server reported one. With a flush interval, output is held back until
+// a word is complete or the interval passes. The prefix is printed ahead of the first token
+fn read_stream(response: ureq::Response, backend: Backend, framing: StreamFraming, flush: Option<Duration>, mut prefix: Option<String>, spinner: &Spinner, describe: impl Fn(io::Error) -> anyhow::Error) -> Result<(String, Option<f32>), anyhow::Error> {
+    let mut output = String::new();
+    let mut time = None;
+    let mut stdout = io::stdout();
+    let mut pending = String::new();
+    let mut last_flush = Instant::now();
+    for line in BufReader::new(response.into_reader()).lines() {
+        let line = match line {
+            Ok(line) => line,
+            // what has streamed so far is already on screen, so keep it as the reply
+            Err(err) if !output.is_empty() => {
+                println!("{}", pending);
+                println!("// Warning: the response was cut off: {:#}", describe(err));
+                return Ok((output, time));
+            }
+            Err(err) => return Err(describe(err)),
+        };
+        let data = match framing {
+            StreamFraming::ServerSentEvents => {
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                data.strip_prefix(' ').unwrap_or(data)
+            }
+            StreamFraming::JsonLines if line.trim().is_empty() => continue,
+            StreamFraming::JsonLines => line.as_str(),
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        // events are normally json chunks, but accept bare text tokens as well
+        let token = match backend.parse_event(data) {
+            Ok((token, chunk_time)) => {
+                time = chunk_time.or(time);
+                token
+            }
+            Err(_) => data.to_string(),
+        };
+        spinner.stop();
+        if let Some(prefix) = prefix.take() {
+            println!("{}", prefix);
+        }
+        output.push_str(&token);
+        pending.push_str(&token);
+        let ready = match flush {
+            None => pending.len(),
+            Some(_) if pending.ends_with(char::is_whitespace) => pending.len(),
+            Some(interval) if last_flush.elapsed() >= interval => pending.len(),
+            // print up to the last finished word, holding back the partial one
+            Some(_) => pending.rfind(char::is_whitespace)
+                .map(|idx| idx + pending[idx..].chars().next().unwrap().len_utf8())
+                .unwrap_or(0),
+        };
+        if ready > 0 {
+            print!("{}", &pending[..ready]);
+            stdout.flush()?;
+            pending.drain(..ready);
+            last_flush = Instant::now();
+        }
+    }
+    println!("{}", pending);
+    Ok((output, time))
+}
+
+// Errors go to stdout alongside the conversation, or to stderr as a line of json for wrapping
+// tools with --json-errors
+fn report_error(json: bool, kind: &str, err: &anyhow::Error) {
+    if json {
+        let causes: Vec<String> = err.chain().skip(1).map(|cause| cause.to_string()).collect();
+        eprintln!("{}", serde_json::json!({"kind": kind, "error": err.to_string(), "causes": causes}));
+    } else if kind == "command" {
+        println!("// Command error: {}", err);
+    } else {
+        println!("// Error: {}", err);
+    }
+}
+
+// Send a single prompt and print only the reply, for use in scripts and one-liners
+async fn run_once(config: &Config, prompt: String) -> Result<(), anyhow::Error> {
+    let sys_prompt = if config.system_prompt_file.is_empty() {
+        DEFAULT_SYSTEM_PROMPT.to_string()
+    } else {
+        read_system_prompt(config)?.unwrap_or_default()
+    };
+    let mut ctx = ChatContext::new(config, sys_prompt)?;
+    ctx.append_transcript(&ctx.context[0]);
+    // a one-shot has nothing worth recovering
+    ctx.recovery_file = None;
+    ctx.send_user_message(include_files(prompt.trim_end())?).await
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Config::parse();
-    println!("Enter the system prompt for this session below: ");
-    let sys_prompt = read_message()?;
-    let mut ctx = ChatContext::new(&config, sys_prompt.into_string())?;
-    ctx.initialize_commands().await?;
+    quiet_panics();
+    let config = Config::parse_from(with_profile(std::env::args().collect())?);
+    let json_errors = config.json_errors;
+    match run(config).await {
+        Err(err) if json_errors => {
+            report_error(true, "fatal", &err);
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+async fn run(config: Config) -> Result<(), anyhow::Error> {
+    if config.check {
+        return ChatContext::new(&config, String::new())?.check().await;
+    }
+    if let Some(prompt) = &config.prompt {
+        return run_once(&config, prompt.clone()).await;
+    }
+    if !io::stdin().is_terminal() {
+        let mut prompt = String::new();
+        io::Read::read_to_string(&mut io::stdin(), &mut prompt).context("Failed to read prompt from stdin")?;
+        return run_once(&config, prompt).await;
+    }
+    if let Some(profile) = &config.profile {
+        println!("// Using profile '{}'", profile);
+    }
+    println!("// {}, change this with --submit-key", config.submit_key.hint());
+    // the lock is held until the session ends
+    let (recovery, recovered) = claim_recovery(&config)?;
+    let mut ctx = match recovered {
+        Some(messages) => {
+            let mut ctx = ChatContext::new(&config, String::new())?;
+            ctx.context = messages;
+            ctx
+        }
+        None => {
+            let Some(sys_prompt) = read_system_prompt(&config)? else {
+                if let Some((path, _)) = &recovery {
+                    remove_recovery(path);
+                }
+                return Ok(());
+            };
+            let ctx = ChatContext::new(&config, sys_prompt)?;
+            ctx.append_transcript(&ctx.context[0]);
+            ctx
+        }
+    };
+    ctx.recovery_file = recovery.as_ref().map(|(path, _)| path.clone());
+    if ctx.commands_enabled {
+        ctx.initialize_commands();
+        if config.preload_embedding_model {
+            ctx.preload_embedding_model();
+        }
+        if let Some(file) = &config.commands_file {
+            ctx.load_commands_file(&expand_tilde(file))?;
+        }
+        match &config.persona_dir {
+            Some(dir) => ctx.load_personas(&expand_tilde(dir))?,
+            None => {
+                if let Some(dir) = dirs::config_dir().map(|dir| dir.join("qmuloai").join("personas")) {
+                    if dir.is_dir() {
+                        ctx.load_personas(&dir)?;
+                    }
+                }
+            }
+        }
+    }
     println!("Now you can start chatting. Further responses will be from the assistant\n--------");
     loop {
-        let prompt = read_message()?;
+        let prompt = read_message(&mut ctx.history, &ctx.commands, ctx.input_options, false)?;
         match prompt {
             InputType::Prompt(prompt) => {
-                ctx.send_user_message(prompt)?;
-                println!("{}", ctx.context[ctx.context.len() - 1].content);
+                let result = match include_files(&prompt) {
+                    Ok(prompt) => ctx.send_user_message(prompt).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    report_error(config.json_errors, "prompt", &err);
+                }
             }
             InputType::Command(cmd) => {
-                if let Err(err) = ctx.run_command(cmd).await {
-                    println!("// Command error: {}", err);
+                match ctx.run_command(cmd).await {
+                    Ok(ControlFlow::Break(())) => break,
+                    Ok(ControlFlow::Continue(())) => {}
+                    Err(err) => report_error(config.json_errors, "command", &err),
                 }
             }
+            InputType::Cancelled => {
+                println!("// Cancelled, press Ctrl+C again to exit");
+            }
+            InputType::Exit => break,
         };
     }
+    // every way out of the session ends up here, so anything to flush on exit belongs below
+    if let Some(path) = &ctx.recovery_file {
+        // the session ended cleanly, so there is nothing left to recover
+        remove_recovery(path);
+    }
+    println!("// Goodbye");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file in a directory of its own, for the caller to remove
+    fn temp_file(name: &str, contents: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("qmulo-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn include_files_leaves_words_that_are_not_files() {
+        let prompt = "@dataclass\nclass X: pass\nthanks @someone, see @/no/such/file.rs";
+        assert_eq!(include_files(prompt).unwrap(), prompt);
+    }
+
+    #[test]
+    fn include_files_fences_existing_files() {
+        let (dir, path) = temp_file("fenced.rs", "fn main() {}\n");
+        let expanded = include_files(&format!("what does @{} do", path.display())).unwrap();
+        assert_eq!(expanded, format!("what does \n\n{}:\n```rs\nfn main() {{}}\n```\n do", path.display()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_files_keeps_trailing_punctuation_out_of_the_path() {
+        let (dir, path) = temp_file("punctuated.txt", "hello");
+        let expanded = include_files(&format!("read @{}, then answer", path.display())).unwrap();
+        assert_eq!(expanded, format!("read \n\n{}:\n```txt\nhello\n```\n, then answer", path.display()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_files_escapes_double_at() {
+        let (dir, path) = temp_file("escaped.txt", "hello");
+        let expanded = include_files(&format!("@@{} and @@me", path.display())).unwrap();
+        assert_eq!(expanded, format!("@{} and @me", path.display()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_files_refuses_large_files() {
+        let (dir, path) = temp_file("large.txt", &"x".repeat(MAX_INCLUDE_BYTES as usize + 1));
+        assert!(include_files(&format!("@{}", path.display())).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wrap_text_breaks_at_spaces() {
+        assert_eq!(wrap_text("the quick brown fox jumps", 10), "the quick\nbrown fox\njumps");
+        assert_eq!(wrap_text("short\n", 10), "short\n");
+    }
+
+    #[test]
+    fn wrap_text_keeps_indentation() {
+        assert_eq!(wrap_text("    - an indented list item to wrap", 20), "    - an indented\n    list item to\n    wrap");
+    }
+
+    #[test]
+    fn wrap_text_leaves_code_blocks_and_long_words() {
+        let code = "```\nlet long_line = some_function(with, many, arguments);\n```";
+        assert_eq!(wrap_text(code, 10), code);
+        assert_eq!(wrap_text("a supercalifragilistic word", 10), "a\nsupercalifragilistic\nword");
+    }
+
+    #[test]
+    fn diff_lines_marks_changes() {
+        assert_eq!(diff_lines("a\nb\nc", "a\nx\nc\nd"), vec![(' ', "a"), ('-', "b"), ('+', "x"), (' ', "c"), ('+', "d")]);
+        assert_eq!(diff_lines("", "a"), vec![('+', "a")]);
+        assert_eq!(diff_lines("a", ""), vec![('-', "a")]);
+    }
+
+    fn context_with(limit: usize, messages: Vec<Message>) -> ChatContext {
+        let config = Config::parse_from(["qmulo", "--max-context-messages", &limit.to_string()]);
+        let mut ctx = ChatContext::new(&config, "sys".into()).unwrap();
+        ctx.context.extend(messages);
+        ctx
+    }
+
+    fn assistant(content: &str) -> Message {
+        Message{role: Role::Assistant, content: content.into(), thinking: None}
+    }
+
+    #[test]
+    fn trim_context_drops_whole_exchanges_after_the_system_prompt() {
+        let mut ctx = context_with(3, vec![
+            Message::user("1".into()), assistant("1"),
+            Message::user("2".into()), assistant("2"),
+            Message::user("3".into()),
+        ]);
+        assert_eq!(ctx.trim_context(), 2);
+        let contents: Vec<&str> = ctx.context.iter().map(|message| message.content.as_str()).collect();
+        assert_eq!(contents, ["sys", "2", "2", "3"]);
+    }
+
+    #[test]
+    fn trim_context_keeps_the_latest_message() {
+        let mut ctx = context_with(1, vec![Message::user("1".into()), assistant("1"), Message::user("2".into())]);
+        assert_eq!(ctx.trim_context(), 2);
+        assert_eq!(ctx.context.len(), 2);
+        assert_eq!(ctx.context[1].content, "2");
+    }
+
+    #[test]
+    fn split_thinking_separates_reasoning() {
+        assert_eq!(split_thinking("<think> hmm </think>\nthe answer"), ("the answer".to_string(), Some("hmm".to_string())));
+        assert_eq!(split_thinking("no reasoning"), ("no reasoning".to_string(), None));
+        assert_eq!(split_thinking("a<think>one</think>b<think>two</think>c"), ("abc".to_string(), Some("one\n\ntwo".to_string())));
+        // a reply cut off mid-thought has no answer yet
+        assert_eq!(split_thinking("<think>still going"), (String::new(), Some("still going".to_string())));
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn merge_profile_puts_settings_before_the_arguments() {
+        let profile = serde_json::json!({"llm_host": "remote:8000", "stream": true, "verbose": false, "temperature": 0.5});
+        let merged = merge_profile(args(&["qmulo", "--profile", "work"]), "work", profile.as_object().unwrap().clone()).unwrap();
+        assert_eq!(merged, args(&["qmulo", "--llm-host", "remote:8000", "--stream", "--temperature", "0.5", "--profile", "work"]));
+    }
+
+    #[test]
+    fn merge_profile_yields_to_given_flags() {
+        let profile = serde_json::json!({"llm_host": "remote:8000", "system_prompt_file": ["a.txt", "b.txt"]});
+        let given = args(&["qmulo", "-Hlocal:9000", "--system-prompt-file=c.txt"]);
+        assert_eq!(merge_profile(given.clone(), "work", profile.as_object().unwrap().clone()).unwrap(), given);
+    }
+
+    #[test]
+    fn merge_profile_repeats_list_values() {
+        let profile = serde_json::json!({"system_prompt_file": ["a.txt", "b.txt"]});
+        let merged = merge_profile(args(&["qmulo"]), "work", profile.as_object().unwrap().clone()).unwrap();
+        assert_eq!(merged, args(&["qmulo", "--system-prompt-file", "a.txt", "--system-prompt-file", "b.txt"]));
+    }
+
+    #[test]
+    fn merge_profile_rejects_objects() {
+        let profile = serde_json::json!({"llm_host": {"name": "remote"}});
+        assert!(merge_profile(args(&["qmulo"]), "work", profile.as_object().unwrap().clone()).is_err());
+    }
 }